@@ -0,0 +1,300 @@
+use chrono::TimeDelta;
+
+use crate::database::Handle;
+use crate::formula;
+use crate::grid;
+use crate::models::{CellRange, CellValue, Fill, FillDirection, NewGridValue, Paste, Position};
+
+/// Largest number of cells written by a paste or a fill
+const MAX_BLOCK_CELLS: u64 = 100_000;
+
+/// Positions are stored as signed 64 bits integers
+pub fn in_sheet(position: &Position) -> bool {
+    position.row <= i64::MAX as u64 && position.column <= i64::MAX as u64
+}
+
+/// Cells written by a paste, the cut cells outside of the block being cleared
+pub fn paste_cells(paste: &Paste) -> Result<Vec<NewGridValue>, String> {
+    let block: u64 = paste.values.iter().map(|row| row.len() as u64).sum();
+    let cut = paste.cut.as_ref().map_or(0, CellRange::cell_count);
+    if block.saturating_add(cut) > MAX_BLOCK_CELLS {
+        return Err(format!(
+            "at most {MAX_BLOCK_CELLS} cells can be written at once"
+        ));
+    }
+    if paste
+        .cut
+        .as_ref()
+        .is_some_and(|cut| !in_sheet(&cut.start) || !in_sheet(&cut.end))
+    {
+        return Err("the cut cells aren't in the sheet".to_string());
+    }
+
+    // The block is written after the cut, the last value of a position wins
+    let mut cells: Vec<NewGridValue> = paste
+        .cut
+        .iter()
+        .flat_map(CellRange::positions)
+        .map(|position| NewGridValue {
+            position,
+            value: None,
+            formula: None,
+        })
+        .collect();
+    for (row, values) in paste.values.iter().enumerate() {
+        for (column, value) in values.iter().enumerate() {
+            let position = Position {
+                column: paste.anchor.column.saturating_add(column as u64),
+                row: paste.anchor.row.saturating_add(row as u64),
+            };
+            if !in_sheet(&position) {
+                return Err("the block doesn't fit in the sheet".to_string());
+            }
+            cells.push(NewGridValue {
+                position,
+                value: value.clone(),
+                formula: None,
+            });
+        }
+    }
+    Ok(cells)
+}
+
+/// Cells written by a fill, line after line
+pub fn fill_targets(fill: &Fill) -> Result<Vec<Position>, String> {
+    let source = &fill.source;
+    // The whole source is read to compute the series
+    if source.cell_count() > MAX_BLOCK_CELLS {
+        return Err(format!(
+            "the source of a fill can't cover more than {MAX_BLOCK_CELLS} cells"
+        ));
+    }
+    let lines = match fill.direction {
+        FillDirection::Down => source.last_column() - source.first_column() + 1,
+        FillDirection::Right => source.last_row() - source.first_row() + 1,
+    };
+    if fill.count == 0 || lines.saturating_mul(fill.count) > MAX_BLOCK_CELLS {
+        return Err(format!(
+            "between 1 and {MAX_BLOCK_CELLS} cells can be filled at once"
+        ));
+    }
+    let mut targets = Vec::new();
+    for line in 0..lines {
+        for step in 1..=fill.count {
+            let position = match fill.direction {
+                FillDirection::Down => Position {
+                    column: source.first_column() + line,
+                    row: source.last_row().saturating_add(step),
+                },
+                FillDirection::Right => Position {
+                    column: source.last_column().saturating_add(step),
+                    row: source.first_row() + line,
+                },
+            };
+            if !in_sheet(&position) {
+                return Err("the filled cells don't fit in the sheet".to_string());
+            }
+            targets.push(position);
+        }
+    }
+    Ok(targets)
+}
+
+/// Values following a series: numbers and dates grow by their average step and other values
+/// are repeated, formulas having their references moved by the distance to the copied cell.
+fn extend_series(
+    series: &[Option<CellValue>],
+    count: u64,
+    direction: FillDirection,
+) -> Vec<Option<CellValue>> {
+    let length = series.len();
+    let steps = i32::try_from(length.saturating_sub(1)).unwrap_or(0);
+    if steps > 0 {
+        let numbers: Option<Vec<f64>> = series
+            .iter()
+            .map(|value| match value {
+                Some(CellValue::Number(number)) => Some(*number),
+                _ => None,
+            })
+            .collect();
+        if let Some(numbers) = numbers {
+            let (first, last) = (numbers[0], numbers[length - 1]);
+            let step = (last - first) / f64::from(steps);
+            return (1..=count)
+                .map(|index| Some(CellValue::Number(last + step * index as f64)))
+                .collect();
+        }
+        let datetimes: Option<Vec<_>> = series
+            .iter()
+            .map(|value| match value {
+                Some(CellValue::Date(date)) => Some(date.and_time(Default::default())),
+                Some(CellValue::Datetime(datetime)) => Some(*datetime),
+                _ => None,
+            })
+            .collect();
+        if let Some(datetimes) = datetimes {
+            let dates = matches!(series[0], Some(CellValue::Date(_)))
+                && series
+                    .iter()
+                    .all(|value| matches!(value, Some(CellValue::Date(_))));
+            let (first, last) = (datetimes[0], datetimes[length - 1]);
+            let mut step = (last - first) / steps;
+            if dates {
+                step = TimeDelta::days(step.num_days());
+            }
+            return (1..=count)
+                .map(|index| {
+                    let datetime = i32::try_from(index)
+                        .ok()
+                        .and_then(|index| last.checked_add_signed(step.checked_mul(index)?))?;
+                    Some(if dates {
+                        CellValue::Date(datetime.date())
+                    } else {
+                        CellValue::Datetime(datetime)
+                    })
+                })
+                .collect();
+        }
+    }
+
+    (0..count as usize)
+        .map(|index| {
+            let copied = index % length;
+            let distance = (length + index - copied) as i64;
+            match &series[copied] {
+                Some(CellValue::Formula(source)) => {
+                    let (rows, columns) = match direction {
+                        FillDirection::Down => (distance, 0),
+                        FillDirection::Right => (0, distance),
+                    };
+                    Some(CellValue::Formula(formula::offset_references(
+                        source, rows, columns,
+                    )))
+                }
+                value => value.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Cells written by a fill, computed from the current content of its source
+pub async fn fill_cells(
+    handle: &Handle,
+    sheet_id: &str,
+    fill: &Fill,
+) -> Result<Vec<NewGridValue>, mongodb::error::Error> {
+    let source = &fill.source;
+    let positions: Vec<Position> = source.positions().collect();
+    let sources = grid::get_sources(handle, sheet_id, &positions).await?;
+    let (lines, length) = match fill.direction {
+        FillDirection::Down => (
+            source.first_column()..=source.last_column(),
+            source.first_row()..=source.last_row(),
+        ),
+        FillDirection::Right => (
+            source.first_row()..=source.last_row(),
+            source.first_column()..=source.last_column(),
+        ),
+    };
+    let position = |line: u64, index: u64| match fill.direction {
+        FillDirection::Down => Position {
+            column: line,
+            row: index,
+        },
+        FillDirection::Right => Position {
+            column: index,
+            row: line,
+        },
+    };
+
+    let mut cells = Vec::new();
+    for line in lines {
+        let series: Vec<Option<CellValue>> = length
+            .clone()
+            .map(|index| sources.get(&position(line, index)).cloned())
+            .collect();
+        let end = *length.end();
+        let values = extend_series(&series, fill.count, fill.direction);
+        cells.extend(
+            values
+                .into_iter()
+                .zip(1..)
+                .map(|(value, step)| NewGridValue {
+                    position: position(line, end + step),
+                    value,
+                    formula: None,
+                }),
+        );
+    }
+    Ok(cells)
+}
+
+#[test]
+fn test_extend_series() {
+    let number = |number| Some(CellValue::Number(number));
+    assert_eq!(
+        extend_series(&[number(1.), number(3.)], 2, FillDirection::Down),
+        [number(5.), number(7.)]
+    );
+    let date = |day| {
+        Some(CellValue::Date(
+            chrono::NaiveDate::from_ymd_opt(2023, 8, day).unwrap(),
+        ))
+    };
+    assert_eq!(
+        extend_series(&[date(1), date(8)], 1, FillDirection::Down),
+        [date(15)]
+    );
+    // A single number is copied, formulas have their references moved
+    let formula = Some(CellValue::Formula("A1*$B$1".to_string()));
+    assert_eq!(
+        extend_series(&[number(2.), formula], 3, FillDirection::Right),
+        [
+            number(2.),
+            Some(CellValue::Formula("C1*$B$1".to_string())),
+            number(2.)
+        ]
+    );
+
+    let paste = Paste {
+        anchor: Position { column: 1, row: 1 },
+        values: vec![vec![number(1.), None], vec![number(2.)]],
+        cut: Some(CellRange {
+            start: Position { column: 0, row: 0 },
+            end: Position { column: 1, row: 1 },
+        }),
+    };
+    let cells = paste_cells(&paste).unwrap();
+    assert_eq!(cells.len(), 7);
+    assert_eq!(cells[4].position, Position { column: 1, row: 1 });
+    let outside = Position {
+        column: 0,
+        row: u64::MAX,
+    };
+    let cut_outside = Paste {
+        cut: Some(CellRange {
+            start: outside.clone(),
+            end: outside,
+        }),
+        ..paste.clone()
+    };
+    assert!(paste_cells(&cut_outside).is_err());
+    let fill = Fill {
+        source: paste.cut.unwrap(),
+        direction: FillDirection::Down,
+        count: 3,
+    };
+    assert_eq!(fill_targets(&fill).unwrap().len(), 6);
+    let column = Fill {
+        source: CellRange {
+            start: Position { column: 0, row: 0 },
+            end: Position {
+                column: 0,
+                row: 1_000_000_000,
+            },
+        },
+        direction: FillDirection::Down,
+        count: 1,
+    };
+    assert!(fill_targets(&column).is_err());
+}
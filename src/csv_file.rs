@@ -0,0 +1,184 @@
+use serde::Deserialize;
+
+use crate::block;
+use crate::formula;
+use crate::models::{CellValue, GridValue, NewGridValue, Position};
+
+/// Largest number of fields written by an export, empty ones included
+const MAX_EXPORT_FIELDS: u64 = 10_000_000;
+
+/// When the fields of an exported CSV are quoted
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quoting {
+    #[default]
+    Necessary,
+    Always,
+    NonNumeric,
+    Never,
+}
+
+impl From<Quoting> for csv::QuoteStyle {
+    fn from(quoting: Quoting) -> Self {
+        match quoting {
+            Quoting::Necessary => csv::QuoteStyle::Necessary,
+            Quoting::Always => csv::QuoteStyle::Always,
+            Quoting::NonNumeric => csv::QuoteStyle::NonNumeric,
+            Quoting::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn delimiter_byte(delimiter: char) -> Result<u8, String> {
+    u8::try_from(delimiter)
+        .ok()
+        .filter(u8::is_ascii)
+        .ok_or_else(|| format!("the delimiter {delimiter:?} is not an ASCII character"))
+}
+
+/// Query parameters of the CSV export
+#[derive(Debug, Deserialize)]
+pub struct ExportOptions {
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    #[serde(default)]
+    pub quoting: Quoting,
+}
+
+/// Query parameters of the CSV import
+#[derive(Debug, Deserialize)]
+pub struct ImportOptions {
+    /// User recorded as the author of the imported cells
+    pub user: String,
+    /// Cell receiving the first field of the first record, `A1` by default
+    pub at: Option<String>,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+}
+
+/// Write the cells as CSV, the first record being the first row of the sheet.
+pub fn write_csv(cells: &[GridValue], options: &ExportOptions) -> Result<Vec<u8>, String> {
+    let rows = cells.iter().map(|cell| cell.position.row + 1).max();
+    let columns = cells.iter().map(|cell| cell.position.column + 1).max();
+    let (rows, columns) = (rows.unwrap_or(0), columns.unwrap_or(0));
+    if rows.saturating_mul(columns) > MAX_EXPORT_FIELDS {
+        return Err(format!(
+            "the sheet spans {rows} rows and {columns} columns, too many to export"
+        ));
+    }
+
+    // Records are written field by field, in the order of the sorted cells
+    let mut cells: Vec<&GridValue> = cells.iter().collect();
+    cells.sort_by_key(|cell| (cell.position.row, cell.position.column));
+    let mut cells = cells.into_iter().peekable();
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter_byte(options.delimiter)?)
+        .quote_style(options.quoting.into())
+        .from_writer(Vec::new());
+    for row in 0..rows {
+        for column in 0..columns {
+            let position = Position { column, row };
+            let mut field = String::new();
+            while let Some(cell) = cells.next_if(|cell| cell.position == position) {
+                field = cell
+                    .value
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+            }
+            writer.write_field(field).map_err(|err| err.to_string())?;
+        }
+        writer
+            .write_record(None::<&[u8]>)
+            .map_err(|err| err.to_string())?;
+    }
+    writer.into_inner().map_err(|err| err.to_string())
+}
+
+/// Read a CSV into the cells to write, the first field of the first record going to `at`.
+/// The type of each field is guessed from its text.
+pub fn read_csv(data: &[u8], options: &ImportOptions) -> Result<Vec<NewGridValue>, String> {
+    let origin = match &options.at {
+        Some(at) => {
+            formula::parse_reference(at).ok_or_else(|| format!("invalid target cell {at}"))?
+        }
+        None => Position::default(),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter_byte(options.delimiter)?)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(data);
+
+    let mut cells = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record.map_err(|err| err.to_string())?;
+        for (column, field) in record.iter().enumerate() {
+            let position = Position {
+                column: origin.column.saturating_add(column as u64),
+                row: origin.row.saturating_add(row as u64),
+            };
+            if !block::in_sheet(&position) {
+                return Err("the CSV doesn't fit in the sheet".to_string());
+            }
+            cells.push(NewGridValue {
+                position,
+                value: CellValue::parse_text(field),
+                formula: None,
+            });
+        }
+    }
+    Ok(cells)
+}
+
+#[test]
+fn test_csv_round_trip() {
+    let options = ImportOptions {
+        user: "alice".to_string(),
+        at: Some("B2".to_string()),
+        delimiter: ';',
+    };
+    let cells = read_csv(b"name;total\n\"a;b\";=SUM(A1:A2)\n", &options).unwrap();
+    assert_eq!(cells.len(), 4);
+    assert_eq!(cells[0].position, Position { column: 1, row: 1 });
+    assert_eq!(cells[3].position, Position { column: 2, row: 2 });
+    assert_eq!(cells[2].value, Some(CellValue::String("a;b".to_string())));
+    assert_eq!(
+        cells[3].value,
+        Some(CellValue::Formula("SUM(A1:A2)".to_string()))
+    );
+
+    // Formula cells are stored with their computed value, which is exported
+    let stored = |column, row, value, formula: Option<&str>| GridValue {
+        position: Position { column, row },
+        value: Some(value),
+        formula: formula.map(str::to_string),
+        ..Default::default()
+    };
+    let cells = vec![
+        stored(2, 2, CellValue::Number(3.), Some("SUM(A1:A2)")),
+        stored(1, 1, CellValue::String("name".to_string()), None),
+        stored(1, 2, CellValue::String("a;b".to_string()), None),
+        stored(2, 1, CellValue::String("total".to_string()), None),
+    ];
+    let options = ExportOptions {
+        delimiter: ';',
+        quoting: Quoting::Necessary,
+    };
+    let exported = String::from_utf8(write_csv(&cells, &options).unwrap()).unwrap();
+    assert_eq!(exported, ";;\n;name;total\n;\"a;b\";3\n");
+
+    // The last row of the sheet has room for a single record
+    let at = |at: &str| ImportOptions {
+        user: "alice".to_string(),
+        at: Some(at.to_string()),
+        delimiter: ',',
+    };
+    assert!(read_csv(b"1,2\n", &at("A9223372036854775808")).is_ok());
+    assert!(read_csv(b"1\n2\n", &at("A9223372036854775808")).is_err());
+    assert!(read_csv(b"1\n", &at("A18446744073709551615")).is_err());
+}
@@ -0,0 +1,383 @@
+use std::{
+    fmt::Write,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use serde_json::Value;
+use sqlx::types::Uuid;
+
+/// Decode a value received in the Postgres binary format.
+/// `udt_name` is the name of the Postgres type, array types being prefixed by `_`.
+pub fn decode_value(udt_name: &str, is_enum: bool, bytes: &[u8]) -> Result<Value, String> {
+    if let Some(element_udt_name) = udt_name.strip_prefix('_') {
+        return decode_array(element_udt_name, is_enum, bytes);
+    }
+    if is_enum {
+        return decode_text(bytes);
+    }
+
+    let mut reader = Reader { udt_name, bytes };
+    let value = match udt_name {
+        "bool" => Value::Bool(reader.take::<1>()?[0] != 0),
+        "int2" => Value::from(i16::from_be_bytes(reader.take()?)),
+        "int4" => Value::from(i32::from_be_bytes(reader.take()?)),
+        "int8" => Value::from(i64::from_be_bytes(reader.take()?)),
+        "oid" => Value::from(u32::from_be_bytes(reader.take()?)),
+        "float4" => float_value(f32::from_be_bytes(reader.take()?).into()),
+        "float8" => float_value(f64::from_be_bytes(reader.take()?)),
+        "numeric" => Value::String(decode_numeric(&mut reader)?),
+        "text" | "varchar" | "bpchar" | "name" | "citext" | "xml" => {
+            return decode_text(reader.rest())
+        }
+        "json" => return decode_json(reader.rest()),
+        "jsonb" => {
+            let version = reader.take::<1>()?[0];
+            if version != 1 {
+                return Err(format!("unsupported jsonb version {version}"));
+            }
+            return decode_json(reader.rest());
+        }
+        "bytea" => return Ok(Value::String(STANDARD.encode(reader.rest()))),
+        "uuid" => Value::String(Uuid::from_bytes(reader.take()?).to_string()),
+        "date" => match i32::from_be_bytes(reader.take()?) {
+            i32::MAX => Value::from("infinity"),
+            i32::MIN => Value::from("-infinity"),
+            days => Value::String(
+                postgres_epoch()
+                    .date()
+                    .checked_add_signed(Duration::days(days.into()))
+                    .ok_or_else(|| out_of_range(udt_name))?
+                    .to_string(),
+            ),
+        },
+        "time" => match i64::from_be_bytes(reader.take()?) {
+            // Postgres accepts the end of the day, which chrono can't represent
+            MICROSECONDS_PER_DAY => Value::from("24:00:00"),
+            us if (0..MICROSECONDS_PER_DAY).contains(&us) => {
+                let time = NaiveTime::from_hms_opt(0, 0, 0).expect("midnight is a valid time")
+                    + Duration::microseconds(us);
+                Value::String(time.format("%H:%M:%S%.f").to_string())
+            }
+            _ => return Err(out_of_range(udt_name)),
+        },
+        "timestamp" => match i64::from_be_bytes(reader.take()?) {
+            i64::MAX => Value::from("infinity"),
+            i64::MIN => Value::from("-infinity"),
+            us => serde_json::to_value(timestamp(udt_name, us)?).unwrap(),
+        },
+        "timestamptz" => match i64::from_be_bytes(reader.take()?) {
+            i64::MAX => Value::from("infinity"),
+            i64::MIN => Value::from("-infinity"),
+            us => serde_json::to_value(Utc.from_utc_datetime(&timestamp(udt_name, us)?)).unwrap(),
+        },
+        "interval" => {
+            let us = i64::from_be_bytes(reader.take()?);
+            let days = i32::from_be_bytes(reader.take()?);
+            let months = i32::from_be_bytes(reader.take()?);
+            Value::String(format_interval(months, days, us))
+        }
+        "inet" | "cidr" => Value::String(decode_inet(&mut reader)?),
+        other => return Err(format!("unsupported type {other}")),
+    };
+    reader.finish()?;
+    Ok(value)
+}
+
+/// Cursor over the bytes of a value, checking that enough bytes are available
+struct Reader<'a> {
+    udt_name: &'a str,
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        if self.bytes.len() < N {
+            return Err(format!(
+                "expected {N} bytes for {}, got {}",
+                self.udt_name,
+                self.bytes.len()
+            ));
+        }
+        let (taken, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(taken.try_into().expect("slice has the requested length"))
+    }
+
+    fn take_slice(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(format!(
+                "expected {len} bytes for {}, got {}",
+                self.udt_name,
+                self.bytes.len()
+            ));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn rest(self) -> &'a [u8] {
+        self.bytes
+    }
+
+    fn finish(self) -> Result<(), String> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} unexpected trailing bytes for {}",
+                self.bytes.len(),
+                self.udt_name
+            ))
+        }
+    }
+}
+
+const MICROSECONDS_PER_DAY: i64 = 86_400_000_000;
+
+fn out_of_range(udt_name: &str) -> String {
+    format!("{udt_name} out of the supported range")
+}
+
+/// Timestamp `us` microseconds after the Postgres epoch, Postgres timestamps going further
+/// in the future than chrono
+fn timestamp(udt_name: &str, us: i64) -> Result<NaiveDateTime, String> {
+    postgres_epoch()
+        .checked_add_signed(Duration::microseconds(us))
+        .ok_or_else(|| out_of_range(udt_name))
+}
+
+fn postgres_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2000, 1, 1)
+        .expect("expected 2000-01-01 to be a valid NaiveDate")
+        .and_hms_opt(0, 0, 0)
+        .expect("expected 2000-01-01T00:00:00 to be a valid NaiveDateTime")
+}
+
+fn float_value(float: f64) -> Value {
+    serde_json::Number::from_f64(float)
+        .map(Value::Number)
+        // NaN and infinities can't be represented as json numbers
+        .unwrap_or_else(|| Value::String(float.to_string()))
+}
+
+fn decode_text(bytes: &[u8]) -> Result<Value, String> {
+    String::from_utf8(bytes.to_vec())
+        .map(Value::String)
+        .map_err(|err| format!("invalid utf-8: {err}"))
+}
+
+fn decode_json(bytes: &[u8]) -> Result<Value, String> {
+    serde_json::from_slice(bytes).map_err(|err| format!("invalid json: {err}"))
+}
+
+/// Numerics are kept as strings to not lose precision
+fn decode_numeric(reader: &mut Reader) -> Result<String, String> {
+    let ndigits = i16::from_be_bytes(reader.take()?);
+    let weight = i16::from_be_bytes(reader.take()?);
+    let sign = u16::from_be_bytes(reader.take()?);
+    let dscale = u16::from_be_bytes(reader.take()?);
+    let digits = (0..ndigits.max(0))
+        .map(|_| reader.take().map(i16::from_be_bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match sign {
+        0x0000 | 0x4000 => {}
+        0xC000 => return Ok("NaN".to_string()),
+        0xD000 => return Ok("Infinity".to_string()),
+        0xF000 => return Ok("-Infinity".to_string()),
+        other => return Err(format!("invalid numeric sign {other:#x}")),
+    }
+    // Digits are in base 10000, the first one being multiplied by 10000^weight
+    let digit = |index: i32| -> i16 {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| digits.get(index))
+            .copied()
+            .unwrap_or(0)
+    };
+
+    let mut numeric = String::new();
+    if sign == 0x4000 {
+        numeric.push('-');
+    }
+    if weight < 0 {
+        numeric.push('0');
+    } else {
+        write!(numeric, "{}", digit(0)).unwrap();
+        for index in 1..=i32::from(weight) {
+            write!(numeric, "{:04}", digit(index)).unwrap();
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut index = i32::from(weight) + 1;
+        while fraction.len() < usize::from(dscale) {
+            write!(fraction, "{:04}", digit(index)).unwrap();
+            index += 1;
+        }
+        fraction.truncate(usize::from(dscale));
+        numeric.push('.');
+        numeric.push_str(&fraction);
+    }
+    Ok(numeric)
+}
+
+/// Format an interval as an ISO 8601 duration
+fn format_interval(months: i32, days: i32, microseconds: i64) -> String {
+    let mut interval = String::from("P");
+    let (years, months) = (months / 12, months % 12);
+    for (amount, unit) in [(years, 'Y'), (months, 'M'), (days, 'D')] {
+        if amount != 0 {
+            write!(interval, "{amount}{unit}").unwrap();
+        }
+    }
+    if microseconds != 0 {
+        interval.push('T');
+        let hours = microseconds / 3_600_000_000;
+        let minutes = microseconds % 3_600_000_000 / 60_000_000;
+        let seconds = microseconds % 60_000_000;
+        for (amount, unit) in [(hours, 'H'), (minutes, 'M')] {
+            if amount != 0 {
+                write!(interval, "{amount}{unit}").unwrap();
+            }
+        }
+        if seconds != 0 {
+            if seconds % 1_000_000 == 0 {
+                write!(interval, "{}S", seconds / 1_000_000).unwrap();
+            } else {
+                let fraction = format!("{:06}", (seconds % 1_000_000).abs());
+                write!(
+                    interval,
+                    "{}{}.{}S",
+                    if seconds < 0 { "-" } else { "" },
+                    (seconds / 1_000_000).abs(),
+                    fraction.trim_end_matches('0')
+                )
+                .unwrap();
+            }
+        }
+    }
+    if interval.len() == 1 {
+        interval.push_str("T0S");
+    }
+    interval
+}
+
+fn decode_inet(reader: &mut Reader) -> Result<String, String> {
+    let [family, bits, is_cidr, length] = reader.take()?;
+    let address = reader.take_slice(length.into())?;
+    let (address, max_bits) = match (family, address.len()) {
+        // Postgres uses its own values for the address families
+        (2, 4) => (
+            Ipv4Addr::from(<[u8; 4]>::try_from(address).unwrap()).to_string(),
+            32,
+        ),
+        (3, 16) => (
+            Ipv6Addr::from(<[u8; 16]>::try_from(address).unwrap()).to_string(),
+            128,
+        ),
+        _ => {
+            return Err(format!(
+                "invalid address family {family} with {length} bytes"
+            ))
+        }
+    };
+    if is_cidr == 0 && bits == max_bits {
+        Ok(address)
+    } else {
+        Ok(format!("{address}/{bits}"))
+    }
+}
+
+fn decode_array(element_udt_name: &str, is_enum: bool, bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader {
+        udt_name: "array",
+        bytes,
+    };
+    let dimensions = i32::from_be_bytes(reader.take()?);
+    let _has_null = i32::from_be_bytes(reader.take()?);
+    let _element_oid = u32::from_be_bytes(reader.take()?);
+    let length = match dimensions {
+        0 => 0,
+        1 => {
+            let length = i32::from_be_bytes(reader.take()?);
+            let _lower_bound = i32::from_be_bytes(reader.take()?);
+            length
+        }
+        _ => return Err(format!("{dimensions}-dimensional arrays are not supported")),
+    };
+
+    let mut elements = Vec::with_capacity(length.max(0) as usize);
+    for _ in 0..length {
+        let element = match i32::from_be_bytes(reader.take()?) {
+            -1 => Value::Null,
+            size => {
+                let size = usize::try_from(size)
+                    .map_err(|_| format!("invalid array element size {size}"))?;
+                decode_value(element_udt_name, is_enum, reader.take_slice(size)?)?
+            }
+        };
+        elements.push(element);
+    }
+    reader.finish()?;
+    Ok(Value::Array(elements))
+}
+
+#[test]
+fn test_decode_numeric() {
+    // 12345.678 -> digits [1, 2345, 6780], weight 1, dscale 3
+    let bytes = [0, 3, 0, 1, 0x40, 0, 0, 3, 0, 1, 0x09, 0x29, 0x1A, 0x7C];
+    assert_eq!(
+        decode_value("numeric", false, &bytes),
+        Ok(Value::from("-12345.678"))
+    );
+    // 0.05 -> digits [500], weight -1, dscale 2
+    let bytes = [0, 1, 0xFF, 0xFF, 0, 0, 0, 2, 0x01, 0xF4];
+    assert_eq!(
+        decode_value("numeric", false, &bytes),
+        Ok(Value::from("0.05"))
+    );
+}
+
+#[test]
+fn test_decode_array_and_errors() {
+    let bytes = [
+        0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 21, 0, 0, 0, 2, 0, 0, 0, 1, // header
+        0, 0, 0, 2, 0, 7, // 7
+        0xFF, 0xFF, 0xFF, 0xFF, // NULL
+    ];
+    assert_eq!(
+        decode_value("_int2", false, &bytes),
+        Ok(serde_json::json!([7, null]))
+    );
+    assert!(decode_value("int4", false, &[0, 1]).is_err());
+    assert_eq!(
+        format_interval(14, 3, 3_723_500_000),
+        "P1Y2M3DT1H2M3.5S".to_string()
+    );
+}
+
+#[test]
+fn test_decode_temporal_bounds() {
+    // 24:00:00 and 25:00:00
+    let end_of_day = MICROSECONDS_PER_DAY.to_be_bytes();
+    assert_eq!(
+        decode_value("time", false, &end_of_day),
+        Ok(Value::from("24:00:00"))
+    );
+    let after_end_of_day = (MICROSECONDS_PER_DAY + 3_600_000_000).to_be_bytes();
+    assert!(decode_value("time", false, &after_end_of_day).is_err());
+    // 5874897-12-31, the last date of Postgres
+    let last_date = 2_145_031_948_i32.to_be_bytes();
+    assert!(decode_value("date", false, &last_date).is_err());
+    let far_timestamp = (i64::MAX - 1).to_be_bytes();
+    assert!(decode_value("timestamp", false, &far_timestamp).is_err());
+    assert!(decode_value("timestamptz", false, &far_timestamp).is_err());
+    assert_eq!(
+        decode_value("date", false, &1_i32.to_be_bytes()),
+        Ok(Value::from("2000-01-02"))
+    );
+}
@@ -0,0 +1,1119 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use crate::models::{Axis, CellValue, Lines, Position};
+
+/// Result of the evaluation of a formula, or value of a cell referenced by a formula
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Empty,
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    /// Spreadsheet error code such as `#DIV/0!`
+    Error(&'static str),
+}
+
+pub const ERROR_DIV_ZERO: &str = "#DIV/0!";
+pub const ERROR_VALUE: &str = "#VALUE!";
+pub const ERROR_NAME: &str = "#NAME?";
+pub const ERROR_CYCLE: &str = "#CYCLE!";
+pub const ERROR_PARSE: &str = "#ERROR!";
+/// Written in place of the references to deleted cells
+pub const ERROR_REF: &str = "#REF!";
+pub const ERRORS: [&str; 6] = [
+    ERROR_DIV_ZERO,
+    ERROR_VALUE,
+    ERROR_NAME,
+    ERROR_CYCLE,
+    ERROR_PARSE,
+    ERROR_REF,
+];
+
+/// Cells a range can cover, every one of them being a node of the dependency graph
+pub const MAX_RANGE_CELLS: u64 = 10_000;
+/// Length of a formula, without its leading `=`
+pub const MAX_FORMULA_LENGTH: usize = 8192;
+/// Parentheses, calls and operators nested in a formula, its evaluation being recursive too
+const MAX_NESTING: usize = 100;
+
+impl Value {
+    /// Interpret the value stored in a cell, dates being compared as their ISO 8601 text
+    pub fn from_cell(value: Option<&CellValue>) -> Self {
+        match value {
+            None | Some(CellValue::Formula(_)) => Value::Empty,
+            Some(CellValue::String(text)) if text.trim().is_empty() => Value::Empty,
+            Some(CellValue::String(text)) => Value::Text(text.clone()),
+            Some(CellValue::Number(number)) => Value::Number(*number),
+            Some(CellValue::Boolean(bool)) => Value::Bool(*bool),
+            Some(date @ (CellValue::Date(_) | CellValue::Datetime(_))) => {
+                Value::Text(date.to_string())
+            }
+            Some(CellValue::Error(code)) => Value::Error(
+                ERRORS
+                    .iter()
+                    .find(|error| *error == code)
+                    .unwrap_or(&ERROR_VALUE),
+            ),
+        }
+    }
+
+    /// Value stored in a formula cell for the result
+    pub fn into_cell(self) -> Option<CellValue> {
+        match self {
+            Value::Empty => None,
+            Value::Number(number) if !number.is_finite() => {
+                Some(CellValue::Error(ERROR_VALUE.to_string()))
+            }
+            Value::Number(number) => Some(CellValue::Number(number)),
+            Value::Text(text) => Some(CellValue::String(text)),
+            Value::Bool(bool) => Some(CellValue::Boolean(bool)),
+            Value::Error(error) => Some(CellValue::Error(error.to_string())),
+        }
+    }
+
+    /// Text shown in the cell
+    pub fn to_text(&self) -> String {
+        match self {
+            Value::Empty => String::new(),
+            Value::Number(number) => number.to_string(),
+            Value::Text(text) => text.clone(),
+            Value::Bool(true) => "TRUE".to_string(),
+            Value::Bool(false) => "FALSE".to_string(),
+            Value::Error(error) => error.to_string(),
+        }
+    }
+
+    fn to_number(&self) -> Result<f64, &'static str> {
+        match self {
+            Value::Empty => Ok(0.),
+            Value::Number(number) => Ok(*number),
+            Value::Bool(bool) => Ok(if *bool { 1. } else { 0. }),
+            Value::Text(text) => text.trim().parse().map_err(|_| ERROR_VALUE),
+            Value::Error(error) => Err(error),
+        }
+    }
+
+    fn to_bool(&self) -> Result<bool, &'static str> {
+        match self {
+            Value::Bool(bool) => Ok(*bool),
+            Value::Text(text) if text.eq_ignore_ascii_case("TRUE") => Ok(true),
+            Value::Text(text) if text.eq_ignore_ascii_case("FALSE") => Ok(false),
+            Value::Error(error) => Err(error),
+            other => other.to_number().map(|number| number != 0.),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Reference(Position),
+    /// Rectangle between two corners, only valid as a function argument
+    Range(Position, Position),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    /// Error code written in the formula, such as `#REF!`
+    Error(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Identifier(String),
+    Operator(char),
+    /// Two characters comparison operators: `<=`, `>=` and `<>`
+    Comparison(&'static str),
+    LeftParen,
+    RightParen,
+    Comma,
+    Colon,
+    Error(&'static str),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                chars.next();
+            }
+            '0'..='9' | '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("invalid number {number}"))?;
+                tokens.push(Token::Number(number));
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        // A doubled quote is an escaped quote
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            text.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            'a'..='z' | 'A'..='Z' | '$' | '_' => {
+                let mut identifier = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '$' || c == '_' || c == '.' {
+                        identifier.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Identifier(identifier));
+            }
+            '<' | '>' => {
+                chars.next();
+                let comparison = match (c, chars.peek()) {
+                    ('<', Some('=')) => Some("<="),
+                    ('>', Some('=')) => Some(">="),
+                    ('<', Some('>')) => Some("<>"),
+                    _ => None,
+                };
+                if let Some(comparison) = comparison {
+                    chars.next();
+                    tokens.push(Token::Comparison(comparison));
+                } else {
+                    tokens.push(Token::Operator(c));
+                }
+            }
+            '+' | '-' | '*' | '/' | '^' | '&' | '=' => {
+                chars.next();
+                tokens.push(Token::Operator(c));
+            }
+            '#' => {
+                let mut code = String::new();
+                for c in chars.by_ref() {
+                    code.push(c);
+                    if c == '!' || c == '?' {
+                        break;
+                    }
+                }
+                let error = ERRORS
+                    .into_iter()
+                    .find(|error| error.eq_ignore_ascii_case(&code))
+                    .ok_or_else(|| format!("unknown error {code}"))?;
+                tokens.push(Token::Error(error));
+            }
+            '(' | ')' | ',' | ';' | ':' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LeftParen,
+                    ')' => Token::RightParen,
+                    ':' => Token::Colon,
+                    _ => Token::Comma,
+                });
+            }
+            other => return Err(format!("unexpected character {other}")),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse an `A1` style reference, columns and rows starting at 0
+pub fn parse_reference(reference: &str) -> Option<Position> {
+    let reference = reference.replace('$', "");
+    let digits_start = reference.find(|c: char| c.is_ascii_digit())?;
+    let (letters, digits) = reference.split_at(digits_start);
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let column = letters
+        .to_ascii_uppercase()
+        .bytes()
+        .try_fold(0u64, |column, letter| {
+            column
+                .checked_mul(26)?
+                .checked_add(u64::from(letter - b'A') + 1)
+        })?
+        - 1;
+    let row = digits.parse::<u64>().ok()?.checked_sub(1)?;
+    Some(Position { column, row })
+}
+
+/// Letters of a column, such as `AA` for the 27th one
+pub fn column_name(mut column: u64) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push(b'A' + (column % 26) as u8);
+        if column < 26 {
+            break;
+        }
+        column = column / 26 - 1;
+    }
+    letters
+        .iter()
+        .rev()
+        .map(|&letter| char::from(letter))
+        .collect()
+}
+
+/// Write a reference moved to `position`, keeping the `$` of the original one
+fn format_reference(original: &str, position: &Position) -> String {
+    let column_absolute = original.starts_with('$');
+    let row_absolute = original[1..].contains('$');
+    format!(
+        "{}{}{}{}",
+        if column_absolute { "$" } else { "" },
+        column_name(position.column),
+        if row_absolute { "$" } else { "" },
+        position.row + 1
+    )
+}
+
+/// Spans of the references of a formula, the two corners of a range being grouped together
+fn reference_spans(formula: &str) -> Vec<Vec<Range<usize>>> {
+    let mut groups: Vec<Vec<Range<usize>>> = Vec::new();
+    // Whether the last token is a colon following a reference
+    let mut in_range = false;
+    let mut chars = formula.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {}
+            '"' => {
+                in_range = false;
+                while let Some((_, c)) = chars.next() {
+                    if c == '"' && chars.next_if(|(_, c)| *c == '"').is_none() {
+                        break;
+                    }
+                }
+            }
+            'a'..='z' | 'A'..='Z' | '$' | '_' => {
+                let mut end = start + 1;
+                while let Some((index, _)) = chars
+                    .next_if(|(_, c)| c.is_ascii_alphanumeric() || matches!(c, '$' | '_' | '.'))
+                {
+                    end = index + 1;
+                }
+                let is_call = formula[end..].trim_start().starts_with('(');
+                if is_call || parse_reference(&formula[start..end]).is_none() {
+                    in_range = false;
+                    continue;
+                }
+                let span = start..end;
+                match groups.last_mut() {
+                    Some(group) if in_range => group.push(span),
+                    _ => groups.push(vec![span]),
+                }
+                in_range = false;
+            }
+            ':' => in_range = groups.last().is_some_and(|group| group.len() == 1),
+            _ => in_range = false,
+        }
+    }
+    groups
+}
+
+/// Rewrite the references of a formula once rows or columns are inserted (deleted).
+/// References to deleted cells become `#REF!`, ranges shrink to their remaining cells.
+pub fn shift_references(formula: &str, lines: &Lines, inserted: bool) -> String {
+    let coordinate = |position: &Position| match lines.axis {
+        Axis::Row => position.row,
+        Axis::Column => position.column,
+    };
+    let with_coordinate = |position: &Position, coordinate: u64| {
+        let mut position = position.clone();
+        match lines.axis {
+            Axis::Row => position.row = coordinate,
+            Axis::Column => position.column = coordinate,
+        }
+        position
+    };
+
+    let mut shifted = String::new();
+    let mut written = 0;
+    for group in reference_spans(formula) {
+        let corners: Vec<Position> = group
+            .iter()
+            .map(|span| parse_reference(&formula[span.clone()]).unwrap())
+            .collect();
+        let moved: Option<Vec<u64>> = match corners.as_slice() {
+            [position] => lines.shift(coordinate(position), inserted).map(|c| vec![c]),
+            [start, end] => {
+                let (low, high) = (
+                    coordinate(start).min(coordinate(end)),
+                    coordinate(start).max(coordinate(end)),
+                );
+                let end_of_deletion = lines.index.saturating_add(lines.count);
+                let new_low = lines
+                    .shift(low, inserted)
+                    .or_else(|| (high >= end_of_deletion).then_some(lines.index));
+                let new_high = lines
+                    .shift(high, inserted)
+                    .or_else(|| (low < lines.index).then(|| lines.index - 1));
+                new_low.zip(new_high).map(|(new_low, new_high)| {
+                    corners
+                        .iter()
+                        .map(|corner| {
+                            if coordinate(corner) == low {
+                                new_low
+                            } else {
+                                new_high
+                            }
+                        })
+                        .collect()
+                })
+            }
+            _ => unreachable!("a reference has one or two corners"),
+        };
+
+        let (first, last) = (group[0].start, group[group.len() - 1].end);
+        shifted.push_str(&formula[written..first]);
+        match moved {
+            Some(coordinates) => {
+                let mut copied = first;
+                for ((span, corner), moved) in group.iter().zip(&corners).zip(coordinates) {
+                    shifted.push_str(&formula[copied..span.start]);
+                    let position = with_coordinate(corner, moved);
+                    shifted.push_str(&format_reference(&formula[span.clone()], &position));
+                    copied = span.end;
+                }
+            }
+            None => shifted.push_str(ERROR_REF),
+        }
+        written = last;
+    }
+    shifted.push_str(&formula[written..]);
+    shifted
+}
+
+/// Rewrite the references of a formula copied `rows` rows and `columns` columns away, like a
+/// fill does. The parts marked with `$` are kept, references moved out of the sheet become `#REF!`.
+pub fn offset_references(formula: &str, rows: i64, columns: i64) -> String {
+    let offset = |original: &str| {
+        let position = parse_reference(original)?;
+        let column_absolute = original.starts_with('$');
+        let row_absolute = original[1..].contains('$');
+        let moved = Position {
+            column: if column_absolute {
+                position.column
+            } else {
+                position.column.checked_add_signed(columns)?
+            },
+            row: if row_absolute {
+                position.row
+            } else {
+                position.row.checked_add_signed(rows)?
+            },
+        };
+        Some(format_reference(original, &moved))
+    };
+
+    let mut offset_formula = String::new();
+    let mut written = 0;
+    for group in reference_spans(formula) {
+        let (first, last) = (group[0].start, group[group.len() - 1].end);
+        offset_formula.push_str(&formula[written..first]);
+        let corners: Option<Vec<String>> = group
+            .iter()
+            .map(|span| offset(&formula[span.clone()]))
+            .collect();
+        match corners {
+            Some(corners) => {
+                let mut copied = first;
+                for (span, corner) in group.iter().zip(corners) {
+                    offset_formula.push_str(&formula[copied..span.start]);
+                    offset_formula.push_str(&corner);
+                    copied = span.end;
+                }
+            }
+            None => offset_formula.push_str(ERROR_REF),
+        }
+        written = last;
+    }
+    offset_formula.push_str(&formula[written..]);
+    offset_formula
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// Parse a nested expression, failing beyond `MAX_NESTING` levels
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        if self.depth == MAX_NESTING {
+            return Err("formula too deeply nested".to_string());
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected {expected:?}, got {token:?}")),
+            None => Err(format!("expected {expected:?}")),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let mut left = self.concatenation()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Operator('=')) => BinaryOp::Eq,
+                Some(Token::Operator('<')) => BinaryOp::Lt,
+                Some(Token::Operator('>')) => BinaryOp::Gt,
+                Some(Token::Comparison("<=")) => BinaryOp::Le,
+                Some(Token::Comparison(">=")) => BinaryOp::Ge,
+                Some(Token::Comparison("<>")) => BinaryOp::Ne,
+                _ => return Ok(left),
+            };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.concatenation()?));
+        }
+    }
+
+    fn concatenation(&mut self) -> Result<Expr, String> {
+        let mut left = self.additive()?;
+        while let Some(Token::Operator('&')) = self.peek() {
+            self.next();
+            left = Expr::Binary(BinaryOp::Concat, Box::new(left), Box::new(self.additive()?));
+        }
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Operator('+')) => BinaryOp::Add,
+                Some(Token::Operator('-')) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        let mut left = self.power()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Operator('*')) => BinaryOp::Mul,
+                Some(Token::Operator('/')) => BinaryOp::Div,
+                _ => return Ok(left),
+            };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.power()?));
+        }
+    }
+
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.unary()?;
+        if let Some(Token::Operator('^')) = self.peek() {
+            self.next();
+            // Right associative
+            return Ok(Expr::Binary(
+                BinaryOp::Pow,
+                Box::new(base),
+                Box::new(self.nested(Self::power)?),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Operator('-')) => {
+                self.next();
+                Ok(Expr::Negate(Box::new(self.nested(Self::unary)?)))
+            }
+            Some(Token::Operator('+')) => {
+                self.next();
+                self.nested(Self::unary)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Text(text)) => Ok(Expr::Text(text)),
+            Some(Token::Error(error)) => Ok(Expr::Error(error)),
+            Some(Token::LeftParen) => {
+                let expr = self.nested(Self::comparison)?;
+                self.expect(Token::RightParen)?;
+                Ok(expr)
+            }
+            Some(Token::Identifier(identifier)) => {
+                if let Some(Token::LeftParen) = self.peek() {
+                    self.next();
+                    let mut arguments = Vec::new();
+                    if let Some(Token::RightParen) = self.peek() {
+                        self.next();
+                    } else {
+                        loop {
+                            arguments.push(self.nested(Self::comparison)?);
+                            match self.next() {
+                                Some(Token::Comma) => continue,
+                                Some(Token::RightParen) => break,
+                                _ => return Err(format!("unclosed call to {identifier}")),
+                            }
+                        }
+                    }
+                    return Ok(Expr::Call(identifier.to_ascii_uppercase(), arguments));
+                }
+                if identifier.eq_ignore_ascii_case("TRUE") {
+                    return Ok(Expr::Bool(true));
+                }
+                if identifier.eq_ignore_ascii_case("FALSE") {
+                    return Ok(Expr::Bool(false));
+                }
+                let start = parse_reference(&identifier)
+                    .ok_or_else(|| format!("invalid reference {identifier}"))?;
+                if let Some(Token::Colon) = self.peek() {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Identifier(end)) => {
+                            let end = parse_reference(&end)
+                                .ok_or_else(|| format!("invalid reference {end}"))?;
+                            if range_size(&start, &end) > MAX_RANGE_CELLS {
+                                return Err(format!(
+                                    "ranges can't cover more than {MAX_RANGE_CELLS} cells"
+                                ));
+                            }
+                            return Ok(Expr::Range(start, end));
+                        }
+                        _ => return Err("expected the end of the range".to_string()),
+                    }
+                }
+                Ok(Expr::Reference(start))
+            }
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err("unexpected end of formula".to_string()),
+        }
+    }
+}
+
+/// Parse a formula, without its leading `=`
+pub fn parse(formula: &str) -> Result<Expr, String> {
+    if formula.len() > MAX_FORMULA_LENGTH {
+        return Err(format!(
+            "formulas can't be longer than {MAX_FORMULA_LENGTH} characters"
+        ));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(formula)?,
+        index: 0,
+        depth: 0,
+    };
+    let expr = parser.comparison()?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {token:?}")),
+    }
+}
+
+/// Number of cells covered by the rectangle between two corners, saturating on overflow
+fn range_size(start: &Position, end: &Position) -> u64 {
+    let columns = start.column.abs_diff(end.column).saturating_add(1);
+    let rows = start.row.abs_diff(end.row).saturating_add(1);
+    columns.saturating_mul(rows)
+}
+
+/// Positions covered by the rectangle between two corners
+pub fn range_positions(start: &Position, end: &Position) -> impl Iterator<Item = Position> {
+    let (columns, rows) = (
+        start.column.min(end.column)..=start.column.max(end.column),
+        start.row.min(end.row)..=start.row.max(end.row),
+    );
+    rows.flat_map(move |row| columns.clone().map(move |column| Position { column, row }))
+}
+
+impl Expr {
+    /// Cells this expression depends on
+    pub fn references(&self) -> HashSet<Position> {
+        let mut references = HashSet::new();
+        self.collect_references(&mut references);
+        references
+    }
+
+    fn collect_references(&self, references: &mut HashSet<Position>) {
+        match self {
+            Expr::Number(_) | Expr::Text(_) | Expr::Bool(_) | Expr::Error(_) => {}
+            Expr::Reference(position) => {
+                references.insert(position.clone());
+            }
+            Expr::Range(start, end) => references.extend(range_positions(start, end)),
+            Expr::Negate(expr) => expr.collect_references(references),
+            Expr::Binary(_, left, right) => {
+                left.collect_references(references);
+                right.collect_references(references);
+            }
+            Expr::Call(_, arguments) => arguments
+                .iter()
+                .for_each(|argument| argument.collect_references(references)),
+        }
+    }
+
+    pub fn evaluate(&self, lookup: &impl Fn(&Position) -> Value) -> Value {
+        match self {
+            Expr::Number(number) => Value::Number(*number),
+            Expr::Text(text) => Value::Text(text.clone()),
+            Expr::Bool(bool) => Value::Bool(*bool),
+            Expr::Reference(position) => lookup(position),
+            Expr::Range(_, _) => Value::Error(ERROR_VALUE),
+            Expr::Negate(expr) => match expr.evaluate(lookup).to_number() {
+                Ok(number) => Value::Number(-number),
+                Err(error) => Value::Error(error),
+            },
+            Expr::Binary(op, left, right) => {
+                binary(*op, left.evaluate(lookup), right.evaluate(lookup))
+            }
+            Expr::Call(function, arguments) => call(function, arguments, lookup),
+            Expr::Error(error) => Value::Error(error),
+        }
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Value {
+    if let Value::Error(error) = left {
+        return Value::Error(error);
+    }
+    if let Value::Error(error) = right {
+        return Value::Error(error);
+    }
+    match op {
+        BinaryOp::Concat => Value::Text(left.to_text() + &right.to_text()),
+        BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            let ordering = match (left.to_number(), right.to_number()) {
+                (Ok(left), Ok(right)) => left.partial_cmp(&right),
+                _ => Some(
+                    left.to_text()
+                        .to_lowercase()
+                        .cmp(&right.to_text().to_lowercase()),
+                ),
+            };
+            let Some(ordering) = ordering else {
+                return Value::Error(ERROR_VALUE);
+            };
+            Value::Bool(match op {
+                BinaryOp::Eq => ordering.is_eq(),
+                BinaryOp::Ne => ordering.is_ne(),
+                BinaryOp::Lt => ordering.is_lt(),
+                BinaryOp::Le => ordering.is_le(),
+                BinaryOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        _ => {
+            let (left, right) = match (left.to_number(), right.to_number()) {
+                (Ok(left), Ok(right)) => (left, right),
+                (Err(error), _) | (_, Err(error)) => return Value::Error(error),
+            };
+            let result = match op {
+                BinaryOp::Add => left + right,
+                BinaryOp::Sub => left - right,
+                BinaryOp::Mul => left * right,
+                BinaryOp::Div if right == 0. => return Value::Error(ERROR_DIV_ZERO),
+                BinaryOp::Div => left / right,
+                _ => left.powf(right),
+            };
+            if result.is_finite() {
+                Value::Number(result)
+            } else {
+                Value::Error(ERROR_VALUE)
+            }
+        }
+    }
+}
+
+/// Values of the arguments, ranges being flattened.
+/// Empty and text cells of ranges are skipped as they are ignored by aggregations.
+fn flatten_arguments(arguments: &[Expr], lookup: &impl Fn(&Position) -> Value) -> Vec<Value> {
+    let mut values = Vec::new();
+    for argument in arguments {
+        match argument {
+            Expr::Range(start, end) => values.extend(
+                range_positions(start, end)
+                    .map(|position| lookup(&position))
+                    .filter(|value| !matches!(value, Value::Empty | Value::Text(_))),
+            ),
+            argument => values.push(argument.evaluate(lookup)),
+        }
+    }
+    values
+}
+
+fn numbers(values: Vec<Value>) -> Result<Vec<f64>, &'static str> {
+    values.iter().map(Value::to_number).collect()
+}
+
+fn call(function: &str, arguments: &[Expr], lookup: &impl Fn(&Position) -> Value) -> Value {
+    let result = match function {
+        "SUM" => {
+            numbers(flatten_arguments(arguments, lookup)).map(|n| Value::Number(n.iter().sum()))
+        }
+        "AVG" | "AVERAGE" => numbers(flatten_arguments(arguments, lookup)).and_then(|n| {
+            if n.is_empty() {
+                Err(ERROR_DIV_ZERO)
+            } else {
+                Ok(Value::Number(n.iter().sum::<f64>() / n.len() as f64))
+            }
+        }),
+        "MIN" => numbers(flatten_arguments(arguments, lookup))
+            .map(|n| Value::Number(n.into_iter().reduce(f64::min).unwrap_or(0.))),
+        "MAX" => numbers(flatten_arguments(arguments, lookup))
+            .map(|n| Value::Number(n.into_iter().reduce(f64::max).unwrap_or(0.))),
+        "COUNT" => Ok(Value::Number(
+            flatten_arguments(arguments, lookup)
+                .iter()
+                .filter(|value| matches!(value, Value::Number(_)))
+                .count() as f64,
+        )),
+        "ABS" | "ROUND" if arguments.is_empty() => Err(ERROR_VALUE),
+        "ABS" => arguments[0]
+            .evaluate(lookup)
+            .to_number()
+            .map(|n| Value::Number(n.abs())),
+        "ROUND" => {
+            let digits = arguments
+                .get(1)
+                .map(|digits| digits.evaluate(lookup).to_number())
+                .unwrap_or(Ok(0.));
+            arguments[0]
+                .evaluate(lookup)
+                .to_number()
+                .and_then(|n| digits.map(|digits| (n, 10f64.powi(digits as i32))))
+                .map(|(n, factor)| Value::Number((n * factor).round() / factor))
+        }
+        "IF" => match arguments {
+            [condition, rest @ ..] if rest.len() <= 2 => {
+                condition.evaluate(lookup).to_bool().map(|condition| {
+                    let branch = if condition { rest.first() } else { rest.get(1) };
+                    branch
+                        .map(|branch| branch.evaluate(lookup))
+                        .unwrap_or(Value::Bool(condition))
+                })
+            }
+            _ => Err(ERROR_VALUE),
+        },
+        "CONCAT" | "CONCATENATE" => {
+            let values = flatten_arguments(arguments, lookup);
+            match values.iter().find_map(|value| match value {
+                Value::Error(error) => Some(*error),
+                _ => None,
+            }) {
+                Some(error) => Err(error),
+                None => Ok(Value::Text(values.iter().map(Value::to_text).collect())),
+            }
+        }
+        _ => Err(ERROR_NAME),
+    };
+    result.unwrap_or_else(Value::Error)
+}
+
+/// Links between formula cells and the cells they reference
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    /// Cells referenced by each formula cell
+    precedents: HashMap<Position, HashSet<Position>>,
+    /// Formula cells referencing each cell
+    dependents: HashMap<Position, HashSet<Position>>,
+}
+
+impl DependencyGraph {
+    /// Register the references of a formula cell, replacing its previous ones
+    pub fn set_formula(&mut self, cell: Position, references: HashSet<Position>) {
+        self.remove_formula(&cell);
+        for reference in &references {
+            self.dependents
+                .entry(reference.clone())
+                .or_default()
+                .insert(cell.clone());
+        }
+        self.precedents.insert(cell, references);
+    }
+
+    /// Forget the references of a cell that no longer holds a formula
+    pub fn remove_formula(&mut self, cell: &Position) {
+        for reference in self.precedents.remove(cell).unwrap_or_default() {
+            if let Some(dependents) = self.dependents.get_mut(&reference) {
+                dependents.remove(cell);
+                if dependents.is_empty() {
+                    self.dependents.remove(&reference);
+                }
+            }
+        }
+    }
+
+    pub fn precedents(&self, cell: &Position) -> impl Iterator<Item = &Position> {
+        self.precedents.get(cell).into_iter().flatten()
+    }
+
+    /// The changed cells and the formula cells to recompute after them, each one after the cells
+    /// it depends on. The second list contains the cells that are part of a reference cycle.
+    pub fn recalculation_order(&self, cells: &[Position]) -> (Vec<Position>, Vec<Position>) {
+        // Tarjan's algorithm finds the strongly connected components, every component being
+        // emitted after the ones depending on it
+        let mut components = Components::default();
+        for cell in cells {
+            if !components.indices.contains_key(cell) {
+                self.strong_connect(cell, &mut components);
+            }
+        }
+
+        let mut order = Vec::new();
+        let mut cycles = Vec::new();
+        for component in components.found.into_iter().rev() {
+            let self_reference = self
+                .dependents
+                .get(&component[0])
+                .is_some_and(|dependents| dependents.contains(&component[0]));
+            if component.len() > 1 || self_reference {
+                cycles.extend(component.iter().cloned());
+            }
+            order.extend(component);
+        }
+        (order, cycles)
+    }
+
+    fn dependents(&self, cell: &Position) -> impl Iterator<Item = &Position> {
+        self.dependents.get(cell).into_iter().flatten()
+    }
+
+    /// Walk the dependents of `root` with an explicit stack of the visited cells and their
+    /// remaining dependents, as chains of formulas can be deeper than the call stack
+    fn strong_connect(&self, root: &Position, components: &mut Components) {
+        components.visit(root);
+        let mut calls = vec![(root.clone(), self.dependents(root))];
+        while let Some((cell, dependents)) = calls.last_mut() {
+            if let Some(dependent) = dependents.next() {
+                if let Some(&dependent_index) = components.indices.get(dependent) {
+                    if components.on_stack.contains(dependent) {
+                        components.lower(cell, dependent_index);
+                    }
+                } else {
+                    components.visit(dependent);
+                    calls.push((dependent.clone(), self.dependents(dependent)));
+                }
+                continue;
+            }
+
+            let Some((cell, _)) = calls.pop() else {
+                break;
+            };
+            let lowlink = components.lowlinks[&cell];
+            if let Some((caller, _)) = calls.last() {
+                components.lower(caller, lowlink);
+            }
+            if lowlink == components.indices[&cell] {
+                let mut component = Vec::new();
+                while let Some(member) = components.stack.pop() {
+                    components.on_stack.remove(&member);
+                    let done = member == cell;
+                    component.push(member);
+                    if done {
+                        break;
+                    }
+                }
+                component.reverse();
+                components.found.push(component);
+            }
+        }
+    }
+}
+
+/// State of the search of strongly connected components
+#[derive(Default)]
+struct Components {
+    indices: HashMap<Position, usize>,
+    lowlinks: HashMap<Position, usize>,
+    stack: Vec<Position>,
+    on_stack: HashSet<Position>,
+    found: Vec<Vec<Position>>,
+}
+
+impl Components {
+    fn visit(&mut self, cell: &Position) {
+        let index = self.indices.len();
+        self.indices.insert(cell.clone(), index);
+        self.lowlinks.insert(cell.clone(), index);
+        self.stack.push(cell.clone());
+        self.on_stack.insert(cell.clone());
+    }
+
+    fn lower(&mut self, cell: &Position, lowlink: usize) {
+        let cell_lowlink = self.lowlinks.get_mut(cell).unwrap();
+        *cell_lowlink = (*cell_lowlink).min(lowlink);
+    }
+}
+
+#[test]
+fn test_formula_evaluation() {
+    let cells: HashMap<Position, Value> = HashMap::from([
+        (parse_reference("A1").unwrap(), Value::Number(2.)),
+        (parse_reference("A2").unwrap(), Value::Number(3.)),
+        (
+            parse_reference("B1").unwrap(),
+            Value::Text("total".to_string()),
+        ),
+    ]);
+    let lookup = |position: &Position| cells.get(position).cloned().unwrap_or(Value::Empty);
+    let evaluate = |formula: &str| parse(formula).unwrap().evaluate(&lookup);
+
+    assert_eq!(evaluate("1 + 2 * 3 ^ 2"), Value::Number(19.));
+    assert_eq!(evaluate("SUM(A1:A3) * -2"), Value::Number(-10.));
+    assert_eq!(evaluate("AVG(A1, A2)"), Value::Number(2.5));
+    assert_eq!(
+        evaluate("IF(MAX(A1:A2) > 2, \"big\", \"small\")"),
+        Value::Text("big".to_string())
+    );
+    assert_eq!(
+        evaluate("CONCAT(B1, \": \", A1 + A2)"),
+        Value::Text("total: 5".to_string())
+    );
+    assert_eq!(evaluate("A1 / (A2 - 3)"), Value::Error(ERROR_DIV_ZERO));
+    assert_eq!(evaluate("NOPE(1)"), Value::Error(ERROR_NAME));
+    assert!(parse("SUM(A1").is_err());
+    assert!(parse("SUM(A1:CV100)").is_ok());
+    assert!(parse("SUM(A1:XFD1048576)").is_err());
+}
+
+#[test]
+fn test_formula_nesting() {
+    let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    let expr = parse(&nested(MAX_NESTING)).unwrap();
+    assert_eq!(expr.evaluate(&|_| Value::Empty), Value::Number(1.));
+    assert_eq!(
+        parse(&nested(1000)).err(),
+        Some("formula too deeply nested".to_string())
+    );
+    assert!(parse(&format!("{}1", "-".repeat(5000))).is_err());
+    assert!(parse(&format!("2{}", "^2".repeat(1000))).is_err());
+    assert!(parse(&format!("SUM({}1{})", "SUM(".repeat(500), ")".repeat(500))).is_err());
+    // Beyond the maximum length, the formula isn't even tokenized
+    assert!(parse(&nested(60_000)).is_err());
+    assert_eq!(
+        CellValue::parse_text(&format!("={}", nested(60_000))),
+        Some(CellValue::String(format!("={}", nested(60_000))))
+    );
+}
+
+#[test]
+fn test_recalculation_order() {
+    let [a1, b1, c1] = ["A1", "B1", "C1"].map(|reference| parse_reference(reference).unwrap());
+    let mut graph = DependencyGraph::default();
+    graph.set_formula(c1.clone(), HashSet::from([a1.clone(), b1.clone()]));
+    graph.set_formula(b1.clone(), HashSet::from([a1.clone()]));
+
+    let (order, cycles) = graph.recalculation_order(std::slice::from_ref(&a1));
+    assert_eq!(order, vec![a1.clone(), b1.clone(), c1.clone()]);
+    assert!(cycles.is_empty());
+
+    graph.set_formula(a1.clone(), HashSet::from([c1.clone()]));
+    let (_, cycles) = graph.recalculation_order(std::slice::from_ref(&a1));
+    assert_eq!(cycles.len(), 3);
+
+    // A filled column of `=A1+1` is a chain deeper than the call stack
+    let mut chain = DependencyGraph::default();
+    let cell = |row| Position { column: 0, row };
+    for row in 1..200_000 {
+        chain.set_formula(cell(row), HashSet::from([cell(row - 1)]));
+    }
+    let (order, cycles) = chain.recalculation_order(&[cell(0)]);
+    assert_eq!(order.len(), 200_000);
+    assert_eq!(order[199_999], cell(199_999));
+    assert!(cycles.is_empty());
+}
+
+#[test]
+fn test_shift_references() {
+    let rows = |index, count| Lines {
+        axis: Axis::Row,
+        index,
+        count,
+    };
+    let formula = r#"SUM(A1:A10) + $B$3 * C5 & "A3" + ABS(A2)"#;
+    assert_eq!(
+        shift_references(formula, &rows(2, 2), true),
+        r#"SUM(A1:A12) + $B$5 * C7 & "A3" + ABS(A2)"#
+    );
+    assert_eq!(
+        shift_references(formula, &rows(2, 2), false),
+        r#"SUM(A1:A8) + #REF! * C3 & "A3" + ABS(A2)"#
+    );
+    assert_eq!(shift_references("A3:B4+1", &rows(1, 5), false), "#REF!+1");
+    assert_eq!(
+        shift_references("SUM(A5 : A2)", &rows(0, 2), false),
+        "SUM(A3 : A1)"
+    );
+    let columns = Lines {
+        axis: Axis::Column,
+        index: 1,
+        count: 26,
+    };
+    assert_eq!(shift_references("Z1+A1", &columns, true), "AZ1+A1");
+    assert!(parse(&shift_references("B1*2", &columns, false)).is_ok());
+    let value = parse("#REF!*2").unwrap().evaluate(&|_| Value::Empty);
+    assert_eq!(value, Value::Error(ERROR_REF));
+}
+
+#[test]
+fn test_offset_references() {
+    assert_eq!(
+        offset_references("SUM(A1:B2) * $C$1 + $D1 + E$1", 2, 1),
+        "SUM(B3:C4) * $C$1 + $D3 + F$1"
+    );
+    assert_eq!(offset_references("A2 + B5", -2, 0), "#REF! + B3");
+}
@@ -1,26 +1,405 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
 use chrono::Utc;
-use mongodb::bson::{doc, to_bson};
+use futures::lock::{Mutex as AsyncMutex, OwnedMutexGuard};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson, Bson};
+
+use crate::database::{self, Handle};
+use crate::formula::{self, DependencyGraph, Value, ERROR_CYCLE, ERROR_PARSE};
+use crate::history;
+use crate::models::{CellValue, Date, GridValue, Lines, NewGridValue, Position};
+use crate::style;
+
+lazy_static! {
+    /// Dependencies between the formula cells of each sheet
+    static ref FORMULAS: RwLock<HashMap<String, DependencyGraph>> = RwLock::new(HashMap::new());
+    /// Serialize the writes of each sheet
+    static ref WRITES: Mutex<HashMap<String, Arc<SheetWrites>>> = Mutex::new(HashMap::new());
+}
+
+/// Writes of a sheet, one at a time. The generation increases with each insertion or deletion
+/// of rows or columns, as the positions checked before it are stale after it.
+#[derive(Default)]
+struct SheetWrites {
+    lock: Arc<AsyncMutex<()>>,
+    generation: AtomicU64,
+}
+
+fn sheet_writes(sheet_id: &str) -> Arc<SheetWrites> {
+    WRITES
+        .lock()
+        .expect("lock on sheet writes")
+        .entry(sheet_id.to_string())
+        .or_default()
+        .clone()
+}
+
+/// Exclusive right to write the cells of a sheet
+pub struct SheetGuard {
+    writes: Arc<SheetWrites>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl SheetGuard {
+    /// Reject the writes whose positions were checked before rows or columns were inserted
+    /// or deleted
+    pub fn cells_moved(&self) {
+        self.writes.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Wait for the other writes of the sheet to be over
+pub async fn lock_sheet(sheet_id: &str) -> SheetGuard {
+    let writes = sheet_writes(sheet_id);
+    let guard = writes.lock.clone().lock_owned().await;
+    SheetGuard {
+        writes,
+        _guard: guard,
+    }
+}
+
+/// Generation of the sheet, to read before checking that the positions to write are locked
+pub fn generation(sheet_id: &str) -> u64 {
+    sheet_writes(sheet_id).generation.load(Ordering::SeqCst)
+}
+
+/// Error of a write whose positions were moved by an insertion or deletion of rows or columns
+/// since they were checked
+#[derive(Debug)]
+pub struct CellsMoved;
+
+/// Position as stored, failing for positions outside of the sheet
+fn position_to_bson(position: &Position) -> Result<Bson, mongodb::error::Error> {
+    Ok(to_bson(position)?)
+}
+
+/// Register the formulas stored in the database in the dependency graph.
+pub async fn load_formulas(handle: &Handle) -> Result<(), mongodb::error::Error> {
+    let cells: Vec<GridValue> = handle
+        .canvas
+        .find(doc! { "formula": { "$type": "string" } }, None)
+        .await?
+        .try_collect()
+        .await?;
+    let mut graphs = FORMULAS.write().expect("write in formulas");
+    for cell in cells {
+        if let Some(Ok(expr)) = cell.formula.as_deref().map(formula::parse) {
+            graphs
+                .entry(cell.sheet)
+                .or_default()
+                .set_formula(cell.position, expr.references());
+        }
+    }
+    Ok(())
+}
+
+/// Drop the dependency graph of a deleted sheet
+pub fn forget_sheet(sheet_id: &str) {
+    FORMULAS
+        .write()
+        .expect("write in formulas")
+        .remove(sheet_id);
+    WRITES
+        .lock()
+        .expect("lock on sheet writes")
+        .remove(sheet_id);
+}
+
+fn evaluate(formula: &str, cells: &HashMap<Position, Option<CellValue>>) -> Option<CellValue> {
+    match formula::parse(formula) {
+        Ok(expr) => expr
+            .evaluate(&|position| Value::from_cell(cells.get(position).and_then(Option::as_ref)))
+            .into_cell(),
+        Err(_) => Some(CellValue::Error(ERROR_PARSE.to_string())),
+    }
+}
+
+/// Content of a cell as typed by its author
+pub async fn get_source(
+    handle: &Handle,
+    sheet_id: &str,
+    position: &Position,
+) -> Result<Option<CellValue>, mongodb::error::Error> {
+    let mut sources = get_sources(handle, sheet_id, std::slice::from_ref(position)).await?;
+    Ok(sources.remove(position))
+}
 
-use crate::database::create_handle;
-use crate::models::{Date, GridValue, NewGridValue};
+/// Content of the non-empty cells among `positions`, as typed by their authors
+pub async fn get_sources(
+    handle: &Handle,
+    sheet_id: &str,
+    positions: &[Position],
+) -> Result<HashMap<Position, CellValue>, mongodb::error::Error> {
+    let positions = positions
+        .iter()
+        .map(position_to_bson)
+        .collect::<Result<Vec<Bson>, _>>()?;
+    let cells: Vec<GridValue> = handle
+        .canvas
+        .find(
+            doc! { "sheet": sheet_id, "position": { "$in": positions } },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+    Ok(cells
+        .into_iter()
+        .filter_map(|cell| {
+            let source = match cell.formula {
+                Some(formula) => CellValue::Formula(formula),
+                None => cell.value?,
+            };
+            Some((cell.position, source))
+        })
+        .collect())
+}
 
-pub async fn create_value(
+/// Write a cell of the whiteboard and recompute the formulas depending on it.
+/// Returns the edited cell and every recomputed cell, as they should be broadcasted.
+pub async fn set_value(
+    handle: &Handle,
+    sheet_id: &str,
     new_box: NewGridValue,
     username: String,
-) -> Result<(), mongodb::error::Error> {
-    let handle = create_handle().await;
-    let position = to_bson(&new_box.position).unwrap();
-    let new = GridValue {
-        timestamp: Date(Utc::now().naive_utc()),
-        position: new_box.position,
-        user: username,
-        value: new_box.value,
+    generation: Option<u64>,
+) -> Result<Vec<NewGridValue>, mongodb::error::Error> {
+    set_values(handle, sheet_id, vec![new_box], username, generation).await
+}
+
+/// Write cells of the whiteboard at once and recompute the formulas depending on them.
+/// Fails with `CellsMoved` if rows or columns were inserted or deleted since `generation`.
+/// Returns the edited cells and every recomputed cell, in evaluation order.
+pub async fn set_values(
+    handle: &Handle,
+    sheet_id: &str,
+    new_boxes: Vec<NewGridValue>,
+    username: String,
+    generation: Option<u64>,
+) -> Result<Vec<NewGridValue>, mongodb::error::Error> {
+    let guard = lock_sheet(sheet_id).await;
+    if generation
+        .is_some_and(|generation| generation != guard.writes.generation.load(Ordering::SeqCst))
+    {
+        return Err(mongodb::error::Error::custom(CellsMoved));
+    }
+    write_values(handle, &guard, sheet_id, new_boxes, username).await
+}
+
+async fn write_values(
+    handle: &Handle,
+    _guard: &SheetGuard,
+    sheet_id: &str,
+    new_boxes: Vec<NewGridValue>,
+    username: String,
+) -> Result<Vec<NewGridValue>, mongodb::error::Error> {
+    // The last value wins when a position is written twice, empty strings clear the cell
+    let edited: HashMap<Position, Option<CellValue>> = new_boxes
+        .into_iter()
+        .map(|new_box| {
+            let value = new_box
+                .value
+                .filter(|value| *value != CellValue::String(String::new()));
+            (new_box.position, value)
+        })
+        .collect();
+    let sources: HashMap<&Position, &str> = edited
+        .iter()
+        .filter_map(|(position, value)| match value {
+            Some(CellValue::Formula(source)) => Some((position, source.as_str())),
+            _ => None,
+        })
+        .collect();
+
+    let (order, cycles, needed) = {
+        let mut graphs = FORMULAS.write().expect("write in formulas");
+        let graph = graphs.entry(sheet_id.to_string()).or_default();
+        for position in edited.keys() {
+            match sources.get(position).map(|source| formula::parse(source)) {
+                Some(Ok(expr)) => graph.set_formula(position.clone(), expr.references()),
+                _ => graph.remove_formula(position),
+            }
+        }
+        let edited_positions: Vec<Position> = edited.keys().cloned().collect();
+        let (order, cycles) = graph.recalculation_order(&edited_positions);
+        let mut needed: Vec<Position> = order
+            .iter()
+            .flat_map(|cell| graph.precedents(cell).cloned().chain([cell.clone()]))
+            .collect();
+        needed.sort_by_key(|position| (position.row, position.column));
+        needed.dedup();
+        (order, cycles, needed)
     };
-    let replaced = handle
-        .find_one_and_replace(doc! { "position": position }, &new, None)
+
+    // Current content of the cells involved in the recomputation
+    let needed = needed
+        .iter()
+        .map(position_to_bson)
+        .collect::<Result<Vec<Bson>, _>>()?;
+    let stored: Vec<GridValue> = handle
+        .canvas
+        .find(
+            doc! { "sheet": sheet_id, "position": { "$in": needed } },
+            None,
+        )
+        .await?
+        .try_collect()
         .await?;
-    if replaced.is_none() {
-        handle.insert_one(new, None).await?;
+    let mut formulas: HashMap<Position, String> = HashMap::new();
+    let mut values: HashMap<Position, Option<CellValue>> = HashMap::new();
+    for cell in stored {
+        if let Some(formula) = cell.formula {
+            formulas.insert(cell.position.clone(), formula);
+        }
+        values.insert(cell.position, cell.value);
     }
-    Ok(())
+    for (position, value) in &edited {
+        match sources.get(position) {
+            Some(source) => formulas.insert(position.clone(), source.to_string()),
+            None => formulas.remove(position),
+        };
+        values.insert(position.clone(), value.clone());
+    }
+
+    let mut changed = Vec::new();
+    for cell in order {
+        if let Some(formula) = formulas.get(&cell) {
+            let value = if cycles.contains(&cell) {
+                Some(CellValue::Error(ERROR_CYCLE.to_string()))
+            } else {
+                evaluate(formula, &values)
+            };
+            values.insert(cell.clone(), value);
+        } else if !edited.contains_key(&cell) {
+            // Stale dependency of a cell that isn't stored anymore
+            continue;
+        }
+        changed.push(NewGridValue {
+            value: values[&cell].clone(),
+            formula: formulas.get(&cell).cloned(),
+            position: cell,
+        });
+    }
+
+    // Every changed cell is recorded in the history, recomputed formulas included
+    let timestamp = Utc::now().naive_utc();
+    let revisions: Vec<GridValue> = changed
+        .iter()
+        .map(|cell| GridValue {
+            timestamp: Date(timestamp),
+            sheet: sheet_id.to_string(),
+            position: cell.position.clone(),
+            user: username.clone(),
+            value: cell.value.clone(),
+            formula: cell.formula.clone(),
+        })
+        .collect();
+
+    // Cleared cells are not stored but formulas always are
+    let (stored, cleared): (Vec<&GridValue>, Vec<&GridValue>) = revisions
+        .iter()
+        .partition(|cell| cell.formula.is_some() || cell.value.is_some());
+    let stored = stored
+        .into_iter()
+        .map(|cell| {
+            let filter = doc! { "sheet": sheet_id, "position": position_to_bson(&cell.position)? };
+            Ok((filter, cell))
+        })
+        .collect::<Result<Vec<_>, mongodb::error::Error>>()?;
+    database::replace_many(handle, &handle.canvas, stored).await?;
+    if !cleared.is_empty() {
+        let cleared = cleared
+            .iter()
+            .map(|cell| position_to_bson(&cell.position))
+            .collect::<Result<Vec<Bson>, _>>()?;
+        handle
+            .canvas
+            .delete_many(
+                doc! { "sheet": sheet_id, "position": { "$in": cleared } },
+                None,
+            )
+            .await?;
+    }
+    history::record(handle, &revisions).await?;
+    Ok(changed)
+}
+
+/// Insert (delete) rows or columns, moving the following cells, their styles and their history,
+/// and rewriting the formulas. Returns the recomputed formula cells, as they should be broadcasted.
+/// The moves stop at the first error, the sheet being partly shifted. Either way, the locks on
+/// positions should then be moved and `SheetGuard::cells_moved` called.
+pub async fn shift_lines(
+    handle: &Handle,
+    guard: &SheetGuard,
+    sheet_id: &str,
+    lines: &Lines,
+    inserted: bool,
+    username: String,
+) -> Result<Vec<NewGridValue>, mongodb::error::Error> {
+    let field = format!("position.{}", lines.field());
+    database::shift_field(&handle.canvas, sheet_id, &field, lines, inserted).await?;
+    style::shift_styles(handle, sheet_id, lines, inserted).await?;
+    // Revisions follow their cell, snapshots showing the cells in the current layout
+    database::shift_field(&handle.history, sheet_id, &field, lines, inserted).await?;
+
+    // Every formula moved, the dependency graph is rebuilt and rewritten formulas recomputed
+    let formula_cells: Vec<GridValue> = handle
+        .canvas
+        .find(
+            doc! { "sheet": sheet_id, "formula": { "$type": "string" } },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+    let mut graph = DependencyGraph::default();
+    let mut rewritten = Vec::new();
+    for cell in formula_cells {
+        let Some(source) = cell.formula else {
+            continue;
+        };
+        let shifted = formula::shift_references(&source, lines, inserted);
+        if let Ok(expr) = formula::parse(&shifted) {
+            graph.set_formula(cell.position.clone(), expr.references());
+        }
+        if shifted != source {
+            rewritten.push(NewGridValue {
+                position: cell.position,
+                value: Some(CellValue::Formula(shifted)),
+                formula: None,
+            });
+        }
+    }
+    FORMULAS
+        .write()
+        .expect("write in formulas")
+        .insert(sheet_id.to_string(), graph);
+    write_values(handle, guard, sheet_id, rewritten, username).await
+}
+
+#[actix_web::test]
+async fn test_stale_write() {
+    let handle = database::create_handle().await;
+    let sheet_id = "test_stale_write";
+    let checked = generation(sheet_id);
+    {
+        let guard = lock_sheet(sheet_id).await;
+        assert!(sheet_writes(sheet_id).lock.try_lock().is_none());
+        // As done by an insertion of rows
+        guard.cells_moved();
+    }
+    assert!(sheet_writes(sheet_id).lock.try_lock().is_some());
+    let err = set_values(
+        &handle,
+        sheet_id,
+        Vec::new(),
+        "alice".to_string(),
+        Some(checked),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.get_custom::<CellsMoved>().is_some());
 }
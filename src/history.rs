@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+use mongodb::options::FindOptions;
+use serde::Deserialize;
+
+use crate::database::Handle;
+use crate::models::{Date, GridValue, Position};
+
+/// Query parameters of the snapshot endpoint
+#[derive(Debug, Deserialize)]
+pub struct SnapshotQuery {
+    /// RFC 3339 timestamp, such as `2023-08-01T12:00:00Z`
+    pub at: DateTime<Utc>,
+}
+
+/// Append revisions of cells to the history.
+pub async fn record(handle: &Handle, revisions: &[GridValue]) -> Result<(), mongodb::error::Error> {
+    if revisions.is_empty() {
+        return Ok(());
+    }
+    handle.history.insert_many(revisions, None).await?;
+    Ok(())
+}
+
+/// Every revision of a cell, the most recent first
+pub async fn list_revisions(
+    handle: &Handle,
+    sheet_id: &str,
+    position: &Position,
+) -> Result<Vec<GridValue>, mongodb::error::Error> {
+    let options = FindOptions::builder()
+        .sort(doc! { "timestamp": -1, "_id": -1 })
+        .build();
+    let filter = doc! {
+        "sheet": sheet_id,
+        "position.row": to_bson(&position.row)?,
+        "position.column": to_bson(&position.column)?,
+    };
+    handle
+        .history
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Rebuild the cells of a sheet as they were at the given time, cleared cells being left out.
+pub async fn snapshot(
+    handle: &Handle,
+    sheet_id: &str,
+    at: NaiveDateTime,
+) -> Result<Vec<GridValue>, mongodb::error::Error> {
+    // Timestamps are stored as ISO 8601 strings, their order is the chronological one
+    let at = to_bson(&Date(at))?;
+    let pipeline = [
+        doc! { "$match": { "sheet": sheet_id, "timestamp": { "$lte": &at } } },
+        doc! { "$sort": { "timestamp": -1, "_id": -1 } },
+        doc! { "$group": { "_id": "$position", "cell": { "$first": "$$ROOT" } } },
+        doc! { "$replaceRoot": { "newRoot": "$cell" } },
+    ];
+    let mut cells: Vec<GridValue> = handle
+        .history
+        .aggregate(pipeline, None)
+        .await?
+        .with_type()
+        .try_collect()
+        .await?;
+
+    // Cells written before the history was recorded only exist in the canvas
+    let recorded: HashSet<Position> = cells.iter().map(|cell| cell.position.clone()).collect();
+    let legacy: Vec<GridValue> = handle
+        .canvas
+        .find(
+            doc! { "sheet": sheet_id, "timestamp": { "$lte": at } },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+    cells.extend(
+        legacy
+            .into_iter()
+            .filter(|cell| !recorded.contains(&cell.position)),
+    );
+    cells.retain(|cell| cell.value.is_some() || cell.formula.is_some());
+    Ok(cells)
+}
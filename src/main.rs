@@ -1,33 +1,132 @@
-#![feature(map_try_insert)]
-#![feature(hash_extract_if)]
 #[warn(unused_extern_crates)]
 #[macro_use]
 extern crate lazy_static;
+mod block;
+mod csv_file;
 mod database;
+mod decode;
+mod formula;
 mod grid;
+mod history;
 mod introspection;
 mod models;
+mod notify;
 mod query;
+mod sheet;
+mod style;
+mod table;
+mod undo;
 mod websocket;
+mod xlsx_file;
 
 use actix_cors::Cors;
+use std::{env, time::Instant};
+
 use actix_web::{
-    error, get, middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer,
-    Responder, Result,
+    delete, error, get,
+    http::header::{self, ContentDisposition},
+    middleware::Logger,
+    patch, post, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder, Result,
 };
 use actix_web_actors::ws;
 use env_logger::Env;
 use introspection::list_columns;
 use mongodb::bson::Uuid;
+use sqlx::PgPool;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    database::{get_grid, Handle},
+    introspection::{list_tables, TableName},
+    models::{
+        ActionKind, LockRelease, NewGridValue, Position, SessionQuery, SheetName, Viewport,
+        Whiteboard,
+    },
+    websocket::{broadcast_to_room, close_room, locked_by_others, release_locks, MyWs, Room},
+};
 
-use crate::{database::get_grid, introspection::list_tables, websocket::MyWs};
+const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
 
-#[get("/ws/whiteboard/{username}")]
-async fn ws_start(
+/// Largest file accepted by the imports
+const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Ensure the sheet exists before using it
+async fn find_sheet(handle: &Handle, sheet_id: &str) -> Result<models::Sheet> {
+    sheet::get_sheet(handle, sheet_id)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to get sheet"))?
+        .ok_or_else(|| error::ErrorNotFound("unknown sheet"))
+}
+
+fn validate_sheet_name(sheet_name: SheetName) -> Result<String> {
+    let name = sheet_name.name.trim();
+    if name.is_empty() {
+        return Err(error::ErrorBadRequest("the sheet name can't be empty"));
+    }
+    Ok(name.to_string())
+}
+
+/// Only callers presenting the `ADMIN_TOKEN` as a bearer token may use the admin endpoints
+fn require_admin(req: &HttpRequest) -> Result<()> {
+    let token = env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| error::ErrorForbidden("the admin endpoints are disabled"))?;
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    // Compared in constant time so the token can't be guessed from the response times
+    let valid =
+        presented.is_some_and(|presented| bool::from(presented.as_bytes().ct_eq(token.as_bytes())));
+    if !valid {
+        return Err(error::ErrorUnauthorized("invalid admin token"));
+    }
+    Ok(())
+}
+
+/// Release the locks of a room, those of a single user if asked, returning them by user
+fn force_release(room: &Room, release: &LockRelease) -> impl Responder {
+    let released = release_locks(room, |_, lock| {
+        release.user.as_ref().is_none_or(|user| *user == lock.owner)
+    });
+    log::info!("Forced the release of the locks of {room:?}: {released:?}");
+    web::Json(released)
+}
+
+#[delete("/admin/locks/whiteboard/{sheet_id}")]
+async fn release_sheet_locks(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    release: web::Query<LockRelease>,
+) -> Result<impl Responder> {
+    require_admin(&req)?;
+    Ok(force_release(&Room::Sheet(path.into_inner().0), &release))
+}
+
+#[delete("/admin/locks/tables/{table_name}")]
+async fn release_table_locks(
     req: HttpRequest,
     path: web::Path<(String,)>,
+    release: web::Query<LockRelease>,
+) -> Result<impl Responder> {
+    require_admin(&req)?;
+    let table_name = TableName::parse(&path.into_inner().0);
+    Ok(force_release(&Room::Table(table_name), &release))
+}
+
+#[get("/ws/whiteboard/{sheet_id}/{username}")]
+async fn ws_start(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    handle: web::Data<Handle>,
+    path: web::Path<(String, String)>,
+    session: web::Query<SessionQuery>,
     stream: web::Payload,
 ) -> Result<HttpResponse, Error> {
+    let (sheet_id, username) = path.into_inner();
+    find_sheet(&handle, &sheet_id).await?;
     let ip = req
         .connection_info()
         .realip_remote_addr()
@@ -36,56 +135,318 @@ async fn ws_start(
     ws::start(
         MyWs {
             uuid: Uuid::new(),
-            username: path.into_inner().0,
+            username,
             ip,
             table: None,
+            sheet: Some(sheet_id),
+            viewport: Viewport::default(),
+            heartbeat: Instant::now(),
+            token: Uuid::new().to_string(),
+            resume: session.into_inner().resume,
+            closed: false,
+            pool: pool.get_ref().clone(),
+            handle: handle.get_ref().clone(),
         },
         &req,
         stream,
     )
 }
 
-#[get("/whiteboard")]
-async fn index() -> Result<impl Responder> {
-    let resp = get_grid()
+#[get("/whiteboard/{sheet_id}")]
+async fn index(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+    viewport: web::Query<Viewport>,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    find_sheet(&handle, &sheet_id).await?;
+    let cells = get_grid(&handle, &sheet_id, &viewport)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to get canvas"))?;
+    let styles = style::get_styles(&handle, &sheet_id, &viewport)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to get styles"))?;
+    Ok(web::Json(Whiteboard { cells, styles }))
+}
+
+#[get("/whiteboard/{sheet_id}/cells/{column}/{row}/revisions")]
+async fn get_revisions(
+    handle: web::Data<Handle>,
+    path: web::Path<(String, u64, u64)>,
+) -> Result<impl Responder> {
+    let (sheet_id, column, row) = path.into_inner();
+    let position = Position { column, row };
+    if !block::in_sheet(&position) {
+        return Err(error::ErrorBadRequest("the cell isn't in the sheet"));
+    }
+    find_sheet(&handle, &sheet_id).await?;
+    let revisions = history::list_revisions(&handle, &sheet_id, &position)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to list revisions"))?;
+    Ok(web::Json(revisions))
+}
+
+#[get("/whiteboard/{sheet_id}/snapshot")]
+async fn get_snapshot(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+    snapshot_query: web::Query<history::SnapshotQuery>,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    find_sheet(&handle, &sheet_id).await?;
+    let cells = history::snapshot(&handle, &sheet_id, snapshot_query.at.naive_utc())
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to rebuild snapshot"))?;
+    Ok(web::Json(cells))
+}
+
+/// Write imported cells unless another user locked some of them, and broadcast them
+async fn import_cells(
+    handle: &Handle,
+    sheet_id: &str,
+    cells: Vec<NewGridValue>,
+    username: &str,
+) -> Result<web::Json<Vec<NewGridValue>>> {
+    let room = Room::Sheet(sheet_id.to_string());
+    let positions: Vec<Position> = cells.iter().map(|cell| cell.position.clone()).collect();
+    if locked_by_others(&room, &positions, username) {
+        return Err(error::ErrorConflict(
+            "some of the cells are locked by other users",
+        ));
+    }
+    let changed = grid::set_values(handle, sheet_id, cells, username.to_string(), None)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to import cells"))?;
+    broadcast_to_room(&room, username, ActionKind::NewGridValues(changed.clone()));
+    Ok(web::Json(changed))
+}
+
+#[get("/whiteboard/{sheet_id}/csv")]
+async fn export_csv(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+    options: web::Query<csv_file::ExportOptions>,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    let sheet = find_sheet(&handle, &sheet_id).await?;
+    let cells = get_grid(&handle, &sheet_id, &Viewport::default())
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to get canvas"))?;
+    let csv = csv_file::write_csv(&cells, &options).map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header(ContentDisposition::attachment(format!(
+            "{}.csv",
+            sheet.name
+        )))
+        .body(csv))
+}
+
+#[post("/whiteboard/{sheet_id}/csv")]
+async fn import_csv(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+    options: web::Query<csv_file::ImportOptions>,
+    body: web::Bytes,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    find_sheet(&handle, &sheet_id).await?;
+    let cells = csv_file::read_csv(&body, &options).map_err(error::ErrorBadRequest)?;
+    import_cells(&handle, &sheet_id, cells, &options.user).await
+}
+
+#[get("/whiteboard/{sheet_id}/xlsx")]
+async fn export_xlsx(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    let sheet = find_sheet(&handle, &sheet_id).await?;
+    let cells = get_grid(&handle, &sheet_id, &Viewport::default())
         .await
         .map_err(|_| error::ErrorInternalServerError("unable to get canvas"))?;
-    Ok(web::Json(resp))
+    let xlsx = xlsx_file::write_sheet(&sheet.name, &cells).map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok()
+        .content_type(XLSX_CONTENT_TYPE)
+        .insert_header(ContentDisposition::attachment(format!(
+            "{}.xlsx",
+            sheet.name
+        )))
+        .body(xlsx))
+}
+
+#[post("/whiteboard/{sheet_id}/xlsx")]
+async fn import_xlsx(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+    options: web::Query<xlsx_file::ImportOptions>,
+    body: web::Bytes,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    find_sheet(&handle, &sheet_id).await?;
+    let cells = xlsx_file::read_sheet(&body, &options).map_err(error::ErrorBadRequest)?;
+    import_cells(&handle, &sheet_id, cells, &options.user).await
+}
+
+#[get("/sheets")]
+async fn get_sheets(handle: web::Data<Handle>) -> Result<impl Responder> {
+    let sheets = sheet::list_sheets(&handle)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to list sheets"))?;
+    Ok(web::Json(sheets))
+}
+
+#[post("/sheets")]
+async fn create_sheet(
+    handle: web::Data<Handle>,
+    body: web::Json<SheetName>,
+) -> Result<impl Responder> {
+    let name = validate_sheet_name(body.into_inner())?;
+    let sheet = sheet::create_sheet(&handle, name)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to create sheet"))?;
+    Ok(web::Json(sheet))
+}
+
+#[patch("/sheets/{sheet_id}")]
+async fn rename_sheet(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+    body: web::Json<SheetName>,
+) -> Result<impl Responder> {
+    let name = validate_sheet_name(body.into_inner())?;
+    let sheet = sheet::rename_sheet(&handle, &path.into_inner().0, name)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to rename sheet"))?
+        .ok_or_else(|| error::ErrorNotFound("unknown sheet"))?;
+    Ok(web::Json(sheet))
+}
+
+#[delete("/sheets/{sheet_id}")]
+async fn delete_sheet(
+    handle: web::Data<Handle>,
+    path: web::Path<(String,)>,
+) -> Result<impl Responder> {
+    let sheet_id = path.into_inner().0;
+    let deleted = sheet::delete_sheet(&handle, &sheet_id)
+        .await
+        .map_err(|_| error::ErrorInternalServerError("unable to delete sheet"))?;
+    if !deleted {
+        return Err(error::ErrorNotFound("unknown sheet"));
+    }
+    close_room(&Room::Sheet(sheet_id), "The sheet was deleted.");
+    Ok(HttpResponse::NoContent())
 }
 
 #[get("/tables")]
-async fn get_tables() -> Result<impl Responder> {
-    let tables = list_tables()
+async fn get_tables(pool: web::Data<PgPool>) -> Result<impl Responder> {
+    let tables = list_tables(&pool)
         .await
         .map_err(|_| error::ErrorInternalServerError("sqlx error unable to list tables"))?;
     Ok(web::Json(tables))
 }
 
 #[get("/tables/{table_name}/columns")]
-async fn get_columns(path: web::Path<(String,)>) -> Result<impl Responder> {
-    let table_name = path.into_inner().0;
-    let columns = list_columns(&table_name)
+async fn get_columns(
+    pool: web::Data<PgPool>,
+    path: web::Path<(String,)>,
+) -> Result<impl Responder> {
+    let table_name = TableName::parse(&path.into_inner().0);
+    let columns = list_columns(&pool, &table_name)
         .await
         .map_err(|_| error::ErrorInternalServerError("sqlx error unable to list columns"))?;
     Ok(web::Json(columns))
 }
 
 #[get("/tables/{table_name}/rows")]
-async fn query_table(path: web::Path<(String,)>) -> Result<impl Responder> {
-    let table_name = path.into_inner().0;
-    let columns = query::query_table(&table_name)
+async fn query_table(
+    pool: web::Data<PgPool>,
+    path: web::Path<(String,)>,
+    rows_query: web::Query<query::RowsQuery>,
+) -> Result<impl Responder> {
+    let table_name = TableName::parse(&path.into_inner().0);
+    let page = query::query_table(&pool, &table_name, &rows_query)
+        .await
+        .map_err(|err| match err {
+            query::QueryError::Invalid(reason) => error::ErrorBadRequest(reason),
+            query::QueryError::Sqlx(err) => {
+                log::error!("Unable to query rows of {table_name}: {err}");
+                error::ErrorInternalServerError("sqlx error unable to query rows")
+            }
+        })?;
+    Ok(web::Json(page))
+}
+
+#[get("/tables/{table_name}/xlsx")]
+async fn export_table_xlsx(
+    pool: web::Data<PgPool>,
+    path: web::Path<(String,)>,
+    rows_query: web::Query<query::RowsQuery>,
+) -> Result<impl Responder> {
+    let table_name = TableName::parse(&path.into_inner().0);
+    let columns = list_columns(&pool, &table_name)
         .await
         .map_err(|_| error::ErrorInternalServerError("sqlx error unable to list columns"))?;
-    Ok(web::Json(columns))
+    // Every matching row is exported, page after page
+    let rows = query::query_all_rows(&pool, &table_name, &rows_query, xlsx_file::MAX_TABLE_ROWS)
+        .await
+        .map_err(|err| match err {
+            query::QueryError::Invalid(reason) => error::ErrorBadRequest(reason),
+            query::QueryError::Sqlx(err) => {
+                log::error!("Unable to query rows of {table_name}: {err}");
+                error::ErrorInternalServerError("sqlx error unable to query rows")
+            }
+        })?;
+    let xlsx = xlsx_file::write_table(&table_name.table, &columns, &rows)
+        .map_err(|_| error::ErrorInternalServerError("unable to write the workbook"))?;
+    Ok(HttpResponse::Ok()
+        .content_type(XLSX_CONTENT_TYPE)
+        .insert_header(ContentDisposition::attachment(format!("{table_name}.xlsx")))
+        .body(xlsx))
+}
+
+#[post("/tables/{table_name}/notifications")]
+async fn enable_notifications(
+    pool: web::Data<PgPool>,
+    path: web::Path<(String,)>,
+) -> Result<impl Responder> {
+    let table_name = TableName::parse(&path.into_inner().0);
+    notify::install_trigger(&pool, &table_name)
+        .await
+        .map_err(|err| match err {
+            query::QueryError::Invalid(reason) => error::ErrorBadRequest(reason),
+            query::QueryError::Sqlx(_) => {
+                error::ErrorInternalServerError("sqlx error unable to install notifications")
+            }
+        })?;
+    Ok(HttpResponse::NoContent())
+}
+
+#[delete("/tables/{table_name}/notifications")]
+async fn disable_notifications(
+    pool: web::Data<PgPool>,
+    path: web::Path<(String,)>,
+) -> Result<impl Responder> {
+    let table_name = TableName::parse(&path.into_inner().0);
+    notify::remove_trigger(&pool, &table_name)
+        .await
+        .map_err(|_| {
+            error::ErrorInternalServerError("sqlx error unable to remove notifications")
+        })?;
+    Ok(HttpResponse::NoContent())
 }
 
 #[get("/ws/table/{table_name}/{username}")]
 async fn ws_start_table(
     req: HttpRequest,
+    pool: web::Data<PgPool>,
+    handle: web::Data<Handle>,
     stream: web::Payload,
     path: web::Path<(String, String)>,
+    session: web::Query<SessionQuery>,
 ) -> Result<impl Responder> {
     let (table_name, username) = path.into_inner();
+    let table_name = TableName::parse(&table_name);
     let ip = req
         .connection_info()
         .realip_remote_addr()
@@ -97,6 +458,14 @@ async fn ws_start_table(
             username,
             ip,
             table: Some(table_name),
+            sheet: None,
+            viewport: Viewport::default(),
+            heartbeat: Instant::now(),
+            token: Uuid::new().to_string(),
+            resume: session.into_inner().resume,
+            closed: false,
+            pool: pool.get_ref().clone(),
+            handle: handle.get_ref().clone(),
         },
         &req,
         stream,
@@ -105,18 +474,55 @@ async fn ws_start_table(
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    dotenvy::dotenv().ok();
     env_logger::init_from_env(Env::default().default_filter_or("info,ferrixcel=debug,sqlx=debug"));
 
+    let pool = database::create_pool();
+    let handle = database::create_handle().await;
+    if let Err(err) = database::create_indexes(&handle).await {
+        log::error!("Unable to create the indexes of the whiteboard: {err}");
+    }
+    match sheet::migrate_legacy_cells(&handle).await {
+        Ok(Some(sheet)) => log::info!("Moved the cells without a sheet to {}", sheet.id),
+        Ok(None) => {}
+        Err(err) => log::error!("Unable to move the cells without a sheet: {err}"),
+    }
+    if let Err(err) = grid::load_formulas(&handle).await {
+        log::error!("Unable to load the formulas of the whiteboard: {err}");
+    }
+    actix_web::rt::spawn(notify::listen(pool.clone()));
+    actix_web::rt::spawn(websocket::sweep_locks());
+    actix_web::rt::spawn(websocket::sweep_sessions());
+
     HttpServer::new(move || {
         let cors = Cors::default().allowed_origin_fn(|_, _req_head| true);
         App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(handle.clone()))
+            .app_data(web::PayloadConfig::new(MAX_UPLOAD_SIZE))
             .wrap(Logger::default())
             .wrap(cors)
             .service(ws_start)
+            .service(ws_start_table)
             .service(index)
+            .service(get_revisions)
+            .service(get_snapshot)
+            .service(export_csv)
+            .service(import_csv)
+            .service(export_xlsx)
+            .service(import_xlsx)
+            .service(get_sheets)
+            .service(create_sheet)
+            .service(rename_sheet)
+            .service(delete_sheet)
             .service(get_tables)
             .service(get_columns)
             .service(query_table)
+            .service(export_table_xlsx)
+            .service(enable_notifications)
+            .service(disable_notifications)
+            .service(release_sheet_locks)
+            .service(release_table_locks)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
@@ -1,8 +1,14 @@
-use chrono::{NaiveDate, NaiveDateTime};
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use mongodb::bson::{self, Bson};
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use strum_macros::AsRefStr;
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::formula;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Date(pub NaiveDateTime);
 
 impl Default for Date {
@@ -19,15 +25,495 @@ impl Default for Date {
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct GridValue {
     pub timestamp: Date,
+    /// Id of the sheet containing the cell
+    #[serde(default)]
+    pub sheet: String,
     pub position: Position,
-    pub value: Option<String>,
+    /// Value shown in the cell, the result of the formula for formula cells
+    pub value: Option<CellValue>,
+    /// Source of the formula without its leading `=`
+    #[serde(default)]
+    pub formula: Option<String>,
     pub user: String,
 }
 
+/// Typed content of a whiteboard cell, such as `{"type": "number", "value": 42}`.
+///
+/// Dates are ISO 8601 strings in JSON and native datetimes in Mongo. Plain strings, as stored
+/// before cells were typed, are read as the text typed by the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellValue {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+    Date(NaiveDate),
+    Datetime(NaiveDateTime),
+    /// Spreadsheet error code such as `#DIV/0!`
+    Error(String),
+    /// Source of a formula without its leading `=`, only written by users
+    Formula(String),
+}
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const DATETIME_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+
+fn parse_datetime(text: &str) -> Option<NaiveDateTime> {
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+}
+
+impl CellValue {
+    /// Guess the type of a text typed by a user, `None` for an empty text.
+    /// Texts starting with `=` too long to be formulas are kept as strings.
+    pub fn parse_text(text: &str) -> Option<Self> {
+        if text.is_empty() {
+            return None;
+        }
+        let trimmed = text.trim();
+        Some(
+            if let Some(source) = text
+                .strip_prefix('=')
+                .filter(|source| source.len() <= formula::MAX_FORMULA_LENGTH)
+            {
+                CellValue::Formula(source.to_string())
+            } else if let Some(number) = trimmed.parse::<f64>().ok().filter(|n| n.is_finite()) {
+                CellValue::Number(number)
+            } else if trimmed.eq_ignore_ascii_case("TRUE") {
+                CellValue::Boolean(true)
+            } else if trimmed.eq_ignore_ascii_case("FALSE") {
+                CellValue::Boolean(false)
+            } else if formula::ERRORS.contains(&trimmed) {
+                CellValue::Error(trimmed.to_string())
+            } else if let Ok(date) = NaiveDate::parse_from_str(trimmed, DATE_FORMAT) {
+                CellValue::Date(date)
+            } else if let Some(datetime) = parse_datetime(trimmed) {
+                CellValue::Datetime(datetime)
+            } else {
+                CellValue::String(text.to_string())
+            },
+        )
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            CellValue::String(_) => "string",
+            CellValue::Number(_) => "number",
+            CellValue::Boolean(_) => "boolean",
+            CellValue::Date(_) => "date",
+            CellValue::Datetime(_) => "datetime",
+            CellValue::Error(_) => "error",
+            CellValue::Formula(_) => "formula",
+        }
+    }
+
+    /// Check a value received from a client or read from the database
+    fn validate(kind: &str, value: Bson) -> Result<Self, String> {
+        let invalid = || format!("invalid value for a cell of type {kind}");
+        let datetime = |value: Bson| match value {
+            Bson::String(text) => parse_datetime(&text)
+                .or_else(|| Some(NaiveDate::parse_from_str(&text, DATE_FORMAT).ok()?.into())),
+            Bson::DateTime(datetime) => {
+                DateTime::from_timestamp_millis(datetime.timestamp_millis()).map(|d| d.naive_utc())
+            }
+            _ => None,
+        };
+        match kind {
+            "string" => match value {
+                Bson::String(text) => Ok(CellValue::String(text)),
+                _ => Err(invalid()),
+            },
+            "number" => match value {
+                Bson::Int32(int) => Ok(CellValue::Number(int.into())),
+                Bson::Int64(int) => Ok(CellValue::Number(int as f64)),
+                Bson::Double(number) if number.is_finite() => Ok(CellValue::Number(number)),
+                _ => Err(invalid()),
+            },
+            "boolean" => match value {
+                Bson::Boolean(bool) => Ok(CellValue::Boolean(bool)),
+                _ => Err(invalid()),
+            },
+            "date" => match datetime(value) {
+                Some(datetime) if datetime.time() == NaiveTime::MIN => {
+                    Ok(CellValue::Date(datetime.date()))
+                }
+                _ => Err(invalid()),
+            },
+            "datetime" => datetime(value).map(CellValue::Datetime).ok_or_else(invalid),
+            "error" => match value {
+                Bson::String(code) if code.starts_with('#') => Ok(CellValue::Error(code)),
+                _ => Err(invalid()),
+            },
+            "formula" => match value {
+                Bson::String(source) => {
+                    let source = source.strip_prefix('=').unwrap_or(&source).to_string();
+                    formula::parse(&source).map_err(|err| format!("invalid formula: {err}"))?;
+                    Ok(CellValue::Formula(source))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(format!("unknown cell type {kind}")),
+        }
+    }
+}
+
+impl fmt::Display for CellValue {
+    /// Text shown in the cell, formulas with their leading `=`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CellValue::String(text) | CellValue::Error(text) => f.write_str(text),
+            CellValue::Number(number) => write!(f, "{number}"),
+            CellValue::Boolean(true) => f.write_str("TRUE"),
+            CellValue::Boolean(false) => f.write_str("FALSE"),
+            CellValue::Date(date) => write!(f, "{}", date.format(DATE_FORMAT)),
+            CellValue::Datetime(datetime) => write!(f, "{}", datetime.format(DATETIME_FORMATS[0])),
+            CellValue::Formula(source) => write!(f, "={source}"),
+        }
+    }
+}
+
+impl Serialize for CellValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The BSON written by the driver is the only format that isn't human readable
+        let native = !serializer.is_human_readable();
+        let mut state = serializer.serialize_struct("CellValue", 2)?;
+        state.serialize_field("type", self.kind())?;
+        match self {
+            CellValue::String(text) | CellValue::Error(text) | CellValue::Formula(text) => {
+                state.serialize_field("value", text)?
+            }
+            CellValue::Number(number) => state.serialize_field("value", number)?,
+            CellValue::Boolean(bool) => state.serialize_field("value", bool)?,
+            CellValue::Date(date) if native => state.serialize_field(
+                "value",
+                &bson::DateTime::from_millis(
+                    date.and_time(NaiveTime::MIN).and_utc().timestamp_millis(),
+                ),
+            )?,
+            CellValue::Datetime(datetime) if native => state.serialize_field(
+                "value",
+                &bson::DateTime::from_millis(datetime.and_utc().timestamp_millis()),
+            )?,
+            CellValue::Date(_) | CellValue::Datetime(_) => {
+                state.serialize_field("value", &self.to_string())?
+            }
+        }
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CellValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Documents read from the database aren't human readable, unlike the values of clients
+        let stored = !deserializer.is_human_readable();
+        match Bson::deserialize(deserializer)? {
+            Bson::String(text) => match CellValue::parse_text(&text) {
+                Some(CellValue::Formula(source)) => {
+                    match CellValue::validate("formula", Bson::String(source)) {
+                        Ok(formula) => Ok(formula),
+                        // Legacy documents may hold text starting with `=` that isn't a formula
+                        Err(_) if stored => Ok(CellValue::String(text)),
+                        Err(err) => Err(de::Error::custom(err)),
+                    }
+                }
+                Some(value) => Ok(value),
+                None => Ok(CellValue::String(text)),
+            },
+            Bson::Document(mut document) => {
+                let kind = match document.remove("type") {
+                    Some(Bson::String(kind)) => kind,
+                    _ => return Err(de::Error::missing_field("type")),
+                };
+                let value = document
+                    .remove("value")
+                    .ok_or_else(|| de::Error::missing_field("value"))?;
+                CellValue::validate(&kind, value).map_err(de::Error::custom)
+            }
+            _ => Err(de::Error::custom("expected a string or a typed cell value")),
+        }
+    }
+}
+
+/// Horizontal alignment of the content of a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Formatting of cells, unset fields are inherited from the row then the column
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Style {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    /// Color of the text, such as `#1f2937`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    /// Excel-like number format, such as `0.00` or `yyyy-mm-dd`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number_format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub align: Option<Align>,
+    /// Width in pixels, only for columns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+}
+
+impl Style {
+    /// Replace the fields set in `other`
+    pub fn merge(&mut self, other: &Style) {
+        fn set<T: Clone>(field: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                field.clone_from(other);
+            }
+        }
+        set(&mut self.bold, &other.bold);
+        set(&mut self.italic, &other.italic);
+        set(&mut self.color, &other.color);
+        set(&mut self.background, &other.background);
+        set(&mut self.number_format, &other.number_format);
+        set(&mut self.align, &other.align);
+        set(&mut self.width, &other.width);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Style::default()
+    }
+}
+
+/// Cell, whole row or whole column a style is attached to
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StyleTarget {
+    Cell(Position),
+    Row(u64),
+    Column(u64),
+}
+
+/// Style stored for a target of a sheet
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StyledTarget {
+    #[serde(default)]
+    pub sheet: String,
+    pub target: StyleTarget,
+    pub style: Style,
+}
+
+/// Style applied to a selection, the fields set in `style` replacing the current ones
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StyleChange {
+    pub targets: Vec<StyleTarget>,
+    pub style: Style,
+    /// Drop the current style of the targets instead of merging with it
+    #[serde(default)]
+    pub reset: bool,
+}
+
+/// Cells of a whiteboard inside a viewport, with the styles of these cells, rows and columns
+#[derive(Debug, Serialize)]
+pub struct Whiteboard {
+    pub cells: Vec<GridValue>,
+    pub styles: Vec<StyledTarget>,
+}
+
+/// Independent whiteboard, its cells reference it by id
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Sheet {
+    pub id: String,
+    pub name: String,
+    pub created: Date,
+}
+
+/// Query parameters of the forced release of locks, every lock of the room by default
+#[derive(Debug, Deserialize)]
+pub struct LockRelease {
+    /// Only release the locks of this user
+    pub user: Option<String>,
+}
+
+/// Query parameters of the websocket connections
+#[derive(Debug, Deserialize)]
+pub struct SessionQuery {
+    /// Token of a dropped session to resume, keeping its locks
+    pub resume: Option<String>,
+}
+
+/// Resumable session, sent to the client as soon as it is connected
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    /// Token to pass as `resume` when reconnecting after a network drop
+    pub token: String,
+    /// Whether a dropped session was resumed, the broadcasts it missed following this message
+    pub resumed: bool,
+    /// Whether every missed broadcast is replayed, the client reloads the whiteboard otherwise
+    pub complete: bool,
+}
+
+/// Name given to a sheet on creation or rename
+#[derive(Debug, Deserialize)]
+pub struct SheetName {
+    pub name: String,
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct Position {
-    column: u64,
-    row: u64,
+    pub column: u64,
+    pub row: u64,
+}
+
+/// Rectangle of the whiteboard, bounds included
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Viewport {
+    pub first_row: u64,
+    pub last_row: u64,
+    pub first_column: u64,
+    pub last_column: u64,
+}
+
+impl Default for Viewport {
+    /// The whole whiteboard, positions are stored as signed 64 bits integers
+    fn default() -> Self {
+        Viewport {
+            first_row: 0,
+            last_row: i64::MAX as u64,
+            first_column: 0,
+            last_column: i64::MAX as u64,
+        }
+    }
+}
+
+impl Viewport {
+    pub fn contains(&self, position: &Position) -> bool {
+        (self.first_row..=self.last_row).contains(&position.row)
+            && (self.first_column..=self.last_column).contains(&position.column)
+    }
+
+    /// Conditions on the stored rows and columns, the bounds being clamped to the positions
+    /// that can be stored
+    pub fn bounds(&self) -> (bson::Document, bson::Document) {
+        let clamp = |bound: u64| i64::try_from(bound).unwrap_or(i64::MAX);
+        (
+            bson::doc! { "$gte": clamp(self.first_row), "$lte": clamp(self.last_row) },
+            bson::doc! { "$gte": clamp(self.first_column), "$lte": clamp(self.last_column) },
+        )
+    }
+}
+
+/// Rectangle of cells between two corners, bounds included
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CellRange {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl CellRange {
+    pub fn first_row(&self) -> u64 {
+        self.start.row.min(self.end.row)
+    }
+
+    pub fn last_row(&self) -> u64 {
+        self.start.row.max(self.end.row)
+    }
+
+    pub fn first_column(&self) -> u64 {
+        self.start.column.min(self.end.column)
+    }
+
+    pub fn last_column(&self) -> u64 {
+        self.start.column.max(self.end.column)
+    }
+
+    /// Number of cells, saturating for huge ranges
+    pub fn cell_count(&self) -> u64 {
+        (self.last_row() - self.first_row())
+            .saturating_add(1)
+            .saturating_mul((self.last_column() - self.first_column()).saturating_add(1))
+    }
+
+    /// Cells row by row
+    pub fn positions(&self) -> impl Iterator<Item = Position> {
+        formula::range_positions(&self.start, &self.end)
+    }
+}
+
+/// Block of values written from `anchor`, one vector per row. `null` values clear their cell.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Paste {
+    pub anchor: Position,
+    pub values: Vec<Vec<Option<CellValue>>>,
+    /// Cells of a cut, cleared unless the block is written over them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cut: Option<CellRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FillDirection {
+    Down,
+    Right,
+}
+
+/// Extension of the pattern of `source` over the next `count` rows or columns
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Fill {
+    pub source: CellRange,
+    pub direction: FillDirection,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    Row,
+    Column,
+}
+
+/// Consecutive rows or columns of the whiteboard, starting at `index`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Lines {
+    pub axis: Axis,
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Lines {
+    /// Name of the coordinate of a position along the axis
+    pub fn field(&self) -> &'static str {
+        match self.axis {
+            Axis::Row => "row",
+            Axis::Column => "column",
+        }
+    }
+
+    /// Coordinate of a position once the lines are inserted (deleted), `None` if it is deleted
+    pub fn shift(&self, coordinate: u64, inserted: bool) -> Option<u64> {
+        if coordinate < self.index {
+            Some(coordinate)
+        } else if inserted {
+            Some(coordinate.saturating_add(self.count))
+        } else if coordinate - self.index < self.count {
+            None
+        } else {
+            Some(coordinate - self.count)
+        }
+    }
+
+    pub fn shift_position(&self, position: &Position, inserted: bool) -> Option<Position> {
+        let mut shifted = position.clone();
+        let coordinate = match self.axis {
+            Axis::Row => &mut shifted.row,
+            Axis::Column => &mut shifted.column,
+        };
+        *coordinate = self.shift(*coordinate, inserted)?;
+        Some(shifted)
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -40,15 +526,148 @@ pub struct Broadcast<'a, T: Serialize> {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct NewGridValue {
     pub position: Position,
-    pub value: Option<String>,
+    /// Formulas are broadcasted with the evaluated value, plain strings have their type guessed
+    pub value: Option<CellValue>,
+    /// Only set by the server on broadcasts of formula cells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formula: Option<String>,
+}
+
+/// Edit of a single cell of a Postgres table row
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CellUpdate {
+    /// Value of the primary key of the edited row
+    pub primary_key: serde_json::Value,
+    pub column: String,
+    pub value: serde_json::Value,
+}
+
+/// Row to insert in a Postgres table, columns missing from `values` take their default value
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NewRow {
+    pub values: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, AsRefStr, Deserialize, Serialize)]
 pub enum ActionKind {
     NewGridValue(NewGridValue),
+    /// Used on whiteboard sessions to write a block of values at once, every written cell
+    /// being locked by the user. Broadcasted as `NewGridValues`.
+    Paste(Paste),
+    /// Used on whiteboard sessions to extend a pattern down or right, every filled cell being
+    /// locked by the user. Broadcasted as `NewGridValues`.
+    Fill(Fill),
+    /// Used only by the server to broadcast the cells written by a paste or a fill at once
+    #[serde(skip_serializing)]
+    NewGridValues(Vec<NewGridValue>),
+    /// Used on table sessions to edit a cell, broadcasted with the committed value
+    UpdateCell(CellUpdate),
+    /// Used on table sessions to insert a row
+    InsertRow(NewRow),
+    /// Used on table sessions to delete rows by primary key, broadcasted with the deleted keys
+    DeleteRows(Vec<serde_json::Value>),
+    /// Used only by the server to broadcast an inserted row
+    #[serde(skip_serializing)]
+    RowInserted(Vec<serde_json::Value>),
+    /// Used only by the server to broadcast a row updated outside of ferrixcel
+    #[serde(skip_serializing)]
+    RowUpdated(Vec<serde_json::Value>),
+    /// Used to revert the last edit of the user in the session, broadcasted as the reverted values
+    Undo,
+    /// Used to replay the last edit undone by the user in the session
+    Redo,
+    /// Used on whiteboard sessions to style the locked cells and any row or column
+    ApplyStyle(StyleChange),
+    /// Used only by the server to broadcast the resulting styles of the styled targets
+    #[serde(skip_serializing)]
+    StyleApplied(Vec<StyledTarget>),
+    /// Used on whiteboard sessions to insert empty rows or columns, the following cells moving
+    /// after them. Broadcasted once the cells are moved so that clients re-index theirs.
+    InsertLines(Lines),
+    /// Used on whiteboard sessions to delete rows or columns with their cells, the following
+    /// cells moving back. Broadcasted once the cells are moved so that clients re-index theirs.
+    DeleteLines(Lines),
+    /// Used to receive only the grid values inside the viewport, all of them by default
+    Viewport(Viewport),
     /// Used to broadcast deselected positions
     Select(Vec<Position>),
     /// Used only by the server to broadcast deselected positions
     #[serde(skip_serializing)]
     Deselect(Vec<Position>),
+    /// Used only by the server to give its resume token to a session when it connects
+    #[serde(skip_serializing)]
+    Session(Session),
+}
+
+#[test]
+fn test_cell_value_serde() {
+    let date = NaiveDate::from_ymd_opt(2023, 8, 1).unwrap();
+    let datetime = date.and_hms_opt(12, 30, 0).unwrap();
+    let parse = |json: &str| serde_json::from_str::<CellValue>(json);
+
+    assert_eq!(
+        parse(r#"{"type": "number", "value": 42}"#).unwrap(),
+        CellValue::Number(42.)
+    );
+    assert_eq!(
+        parse(r#"{"type": "datetime", "value": "2023-08-01T12:30:00"}"#).unwrap(),
+        CellValue::Datetime(datetime)
+    );
+    assert_eq!(
+        parse(r#"{"type": "formula", "value": "=SUM(A1:A2)"}"#).unwrap(),
+        CellValue::Formula("SUM(A1:A2)".to_string())
+    );
+    assert!(parse(r#"{"type": "number", "value": "42"}"#).is_err());
+    assert!(parse(r#"{"type": "date", "value": "2023-08-01T12:30:00"}"#).is_err());
+    assert!(parse(r#"{"type": "formula", "value": "SUM(A1"}"#).is_err());
+    assert!(parse(r#"{"type": "colour", "value": "red"}"#).is_err());
+    assert_eq!(
+        serde_json::to_string(&CellValue::Date(date)).unwrap(),
+        r#"{"type":"date","value":"2023-08-01"}"#
+    );
+
+    // Dates are native datetimes in the documents written by the driver
+    let cell = GridValue {
+        value: Some(CellValue::Date(date)),
+        ..Default::default()
+    };
+    let bytes = bson::to_vec(&cell).unwrap();
+    let document = bson::Document::from_reader(bytes.as_slice()).unwrap();
+    let stored = document.get_document("value").unwrap();
+    assert!(matches!(stored.get("value"), Some(Bson::DateTime(_))));
+    let read: GridValue = bson::from_slice(&bytes).unwrap();
+    assert_eq!(read.value, Some(CellValue::Date(date)));
+
+    // Legacy documents store the text of the cell
+    let mut legacy = document;
+    legacy.insert("value", "2.5");
+    let read: GridValue = bson::from_slice(&bson::to_vec(&legacy).unwrap()).unwrap();
+    assert_eq!(read.value, Some(CellValue::Number(2.5)));
+    legacy.insert("value", "total");
+    let read: GridValue = bson::from_slice(&bson::to_vec(&legacy).unwrap()).unwrap();
+    assert_eq!(read.value, Some(CellValue::String("total".to_string())));
+    legacy.insert("value", "=VLOOKUP(A1,B:C,2)");
+    let read: GridValue = bson::from_slice(&bson::to_vec(&legacy).unwrap()).unwrap();
+    assert_eq!(
+        read.value,
+        Some(CellValue::String("=VLOOKUP(A1,B:C,2)".to_string()))
+    );
+    assert!(parse(r#""=VLOOKUP(A1,B:C,2)""#).is_err());
+}
+
+#[test]
+fn test_viewport_bounds() {
+    let viewport = Viewport {
+        first_row: i64::MAX as u64 - 10,
+        last_row: u64::MAX,
+        first_column: 5,
+        last_column: 20,
+    };
+    let (rows, columns) = viewport.bounds();
+    assert_eq!(rows, bson::doc! { "$gte": i64::MAX - 10, "$lte": i64::MAX });
+    assert_eq!(columns, bson::doc! { "$gte": 5_i64, "$lte": 20_i64 });
+    assert!(viewport.contains(&Position {
+        row: i64::MAX as u64,
+        column: 20,
+    }));
 }
@@ -0,0 +1,236 @@
+use std::collections::{hash_map::Entry, HashMap};
+use std::time::Duration;
+
+use futures::StreamExt;
+use log::{debug, error, info};
+use serde::Deserialize;
+use sqlx::postgres::{PgListener, PgPool};
+
+use crate::{
+    introspection::{self, ColumnInfo, TableName},
+    models::ActionKind,
+    query::QueryError,
+    table,
+    websocket::{broadcast_to_room, room_has_users, Room},
+};
+
+/// Postgres channel on which the triggers publish row changes
+const CHANNEL: &str = "ferrixcel_changes";
+/// Name used as the author of the changes made outside of ferrixcel
+const EXTERNAL_USER: &str = "postgres";
+/// Largest number of notifications dispatched together
+const MAX_BATCH_SIZE: usize = 1000;
+
+const NOTIFY_FUNCTION: &str = "CREATE OR REPLACE FUNCTION ferrixcel_notify() RETURNS trigger AS $$
+    DECLARE
+        changed jsonb;
+        previous jsonb;
+        primary_key jsonb;
+        previous_key jsonb;
+    BEGIN
+        -- Changes made by ferrixcel are already broadcasted to its users
+        IF current_setting('ferrixcel.origin', true) = 'ferrixcel' THEN
+            RETURN NULL;
+        END IF;
+        IF TG_OP = 'DELETE' THEN
+            changed := to_jsonb(OLD);
+        ELSE
+            changed := to_jsonb(NEW);
+        END IF;
+        IF TG_OP = 'UPDATE' THEN
+            previous := to_jsonb(OLD);
+        END IF;
+        -- The arguments are the primary key columns, composite keys are sent as arrays
+        IF TG_NARGS = 1 THEN
+            primary_key := changed -> TG_ARGV[0];
+            previous_key := previous -> TG_ARGV[0];
+        ELSE
+            primary_key := '[]'::jsonb;
+            previous_key := '[]'::jsonb;
+            FOR i IN 0 .. TG_NARGS - 1 LOOP
+                primary_key := primary_key || jsonb_build_array(changed -> TG_ARGV[i]);
+                previous_key := previous_key || jsonb_build_array(previous -> TG_ARGV[i]);
+            END LOOP;
+        END IF;
+        -- Only sent when an update changes the primary key
+        IF previous IS NULL OR previous_key = primary_key THEN
+            previous_key := NULL;
+        END IF;
+        PERFORM pg_notify('ferrixcel_changes', json_build_object(
+            'schema', TG_TABLE_SCHEMA,
+            'table', TG_TABLE_NAME,
+            'operation', TG_OP,
+            'primary_key', primary_key,
+            'previous_key', previous_key
+        )::text);
+        RETURN NULL;
+    END;
+    $$ LANGUAGE plpgsql;";
+
+#[derive(Debug, Deserialize)]
+struct RowChange {
+    #[serde(flatten)]
+    table: TableName,
+    operation: String,
+    primary_key: serde_json::Value,
+    /// Primary key of an updated row before the update, when it changed
+    #[serde(default)]
+    previous_key: Option<serde_json::Value>,
+}
+
+/// Install the trigger publishing every row change of the table to ferrixcel.
+pub async fn install_trigger(pool: &PgPool, table_name: &TableName) -> Result<(), QueryError> {
+    let columns = introspection::list_columns(pool, table_name).await?;
+    let primary_key = introspection::primary_key(&columns);
+    if primary_key.is_empty() {
+        return Err(QueryError::Invalid(
+            "changes of a table without primary key can't be notified".to_string(),
+        ));
+    }
+    let arguments: Vec<String> = primary_key
+        .iter()
+        .map(|c| format!("'{}'", c.column_name.replace('\'', "''")))
+        .collect();
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query(NOTIFY_FUNCTION)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query(&format!(
+        "DROP TRIGGER IF EXISTS ferrixcel_notify ON {};",
+        table_name.quoted()
+    ))
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER ferrixcel_notify AFTER INSERT OR UPDATE OR DELETE ON {}
+         FOR EACH ROW EXECUTE FUNCTION ferrixcel_notify({});",
+        table_name.quoted(),
+        arguments.join(", ")
+    ))
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    info!("Installed change notifications on {table_name}");
+    Ok(())
+}
+
+/// Remove the change notification trigger of the table.
+pub async fn remove_trigger(pool: &PgPool, table_name: &TableName) -> Result<(), sqlx::Error> {
+    // Resolving the columns ensures the table exists before using its name in the query
+    introspection::list_columns(pool, table_name).await?;
+    sqlx::query(&format!(
+        "DROP TRIGGER IF EXISTS ferrixcel_notify ON {};",
+        table_name.quoted()
+    ))
+    .execute(pool)
+    .await?;
+
+    info!("Removed change notifications on {table_name}");
+    Ok(())
+}
+
+/// Broadcast the row changes notified together, the columns of each table being listed once
+async fn dispatch(pool: &PgPool, changes: Vec<RowChange>) {
+    let mut columns = HashMap::new();
+    for change in changes {
+        debug!("Row change: {change:?}");
+        if let Err(err) = dispatch_change(pool, &mut columns, change).await {
+            error!("Unable to dispatch row change: {err}");
+        }
+    }
+}
+
+async fn dispatch_change(
+    pool: &PgPool,
+    columns: &mut HashMap<TableName, Vec<ColumnInfo>>,
+    change: RowChange,
+) -> Result<(), QueryError> {
+    let room = Room::Table(change.table.clone());
+    if !room_has_users(&room) {
+        return Ok(());
+    }
+    let mut actions = Vec::new();
+    // A row whose primary key changed is removed, then inserted with its new key
+    let moved = change.previous_key.is_some();
+    if let Some(previous_key) = change.previous_key {
+        actions.push(ActionKind::DeleteRows(vec![previous_key]));
+    }
+    match change.operation.as_str() {
+        "DELETE" => actions.push(ActionKind::DeleteRows(vec![change.primary_key])),
+        operation => {
+            let columns = match columns.entry(change.table.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(introspection::list_columns(pool, &change.table).await?)
+                }
+            };
+            // A row already deleted again has its deletion notified as well
+            if let Some(row) =
+                table::fetch_row(pool, &change.table, columns, &change.primary_key).await?
+            {
+                actions.push(if operation == "INSERT" || moved {
+                    ActionKind::RowInserted(row)
+                } else {
+                    ActionKind::RowUpdated(row)
+                });
+            }
+        }
+    }
+    for action in actions {
+        broadcast_to_room(&room, EXTERNAL_USER, action);
+    }
+    Ok(())
+}
+
+/// Replace the function of the triggers installed by a previous version of ferrixcel
+async fn upgrade_function(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let installed: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_proc WHERE proname = 'ferrixcel_notify');",
+    )
+    .fetch_one(pool)
+    .await?;
+    if installed {
+        sqlx::query(NOTIFY_FUNCTION).execute(pool).await?;
+    }
+    Ok(())
+}
+
+async fn listen_changes(pool: &PgPool) -> Result<(), sqlx::Error> {
+    if let Err(err) = upgrade_function(pool).await {
+        error!("Unable to upgrade the change notification function: {err}");
+    }
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    info!("Listening to row changes on {CHANNEL}");
+
+    // The notifications already received are dispatched together, as a bulk change sends many
+    let mut notifications = listener.into_stream().ready_chunks(MAX_BATCH_SIZE);
+    while let Some(notifications) = notifications.next().await {
+        let mut changes = Vec::with_capacity(notifications.len());
+        for notification in notifications {
+            let notification = notification?;
+            match serde_json::from_str::<RowChange>(notification.payload()) {
+                Ok(change) => changes.push(change),
+                Err(err) => error!(
+                    "Unable to parse row change {}: {err}",
+                    notification.payload()
+                ),
+            }
+        }
+        dispatch(pool, changes).await;
+    }
+    Ok(())
+}
+
+/// Forward the row changes notified by Postgres to the users of the changed tables,
+/// reconnecting when the connection is lost.
+pub async fn listen(pool: PgPool) {
+    loop {
+        if let Err(err) = listen_changes(&pool).await {
+            error!("Row changes listener stopped: {err}");
+        }
+        actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,126 @@
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Uuid};
+use mongodb::options::FindOptions;
+
+use crate::database::Handle;
+use crate::grid;
+use crate::models::{Date, Sheet};
+
+/// Name of the sheet receiving the cells stored before the whiteboard had sheets
+const LEGACY_SHEET_NAME: &str = "Sheet 1";
+
+/// Sheets ordered by creation date
+pub async fn list_sheets(handle: &Handle) -> Result<Vec<Sheet>, mongodb::error::Error> {
+    let options = FindOptions::builder().sort(doc! { "created": 1 }).build();
+    handle
+        .sheets
+        .find(doc! {}, options)
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn get_sheet(
+    handle: &Handle,
+    sheet_id: &str,
+) -> Result<Option<Sheet>, mongodb::error::Error> {
+    handle.sheets.find_one(doc! { "id": sheet_id }, None).await
+}
+
+pub async fn create_sheet(handle: &Handle, name: String) -> Result<Sheet, mongodb::error::Error> {
+    let sheet = Sheet {
+        id: Uuid::new().to_string(),
+        name,
+        created: Date(Utc::now().naive_utc()),
+    };
+    handle.sheets.insert_one(&sheet, None).await?;
+    Ok(sheet)
+}
+
+/// Rename a sheet, `None` if it doesn't exist.
+pub async fn rename_sheet(
+    handle: &Handle,
+    sheet_id: &str,
+    name: String,
+) -> Result<Option<Sheet>, mongodb::error::Error> {
+    let renamed = handle
+        .sheets
+        .update_one(
+            doc! { "id": sheet_id },
+            doc! { "$set": { "name": name } },
+            None,
+        )
+        .await?;
+    if renamed.matched_count == 0 {
+        return Ok(None);
+    }
+    get_sheet(handle, sheet_id).await
+}
+
+/// Delete a sheet with all its cells and styles, returning whether it existed.
+pub async fn delete_sheet(handle: &Handle, sheet_id: &str) -> Result<bool, mongodb::error::Error> {
+    let deleted = handle
+        .sheets
+        .delete_one(doc! { "id": sheet_id }, None)
+        .await?;
+    if deleted.deleted_count == 0 {
+        return Ok(false);
+    }
+    handle
+        .canvas
+        .delete_many(doc! { "sheet": sheet_id }, None)
+        .await?;
+    handle
+        .styles
+        .delete_many(doc! { "sheet": sheet_id }, None)
+        .await?;
+    grid::forget_sheet(sheet_id);
+    Ok(true)
+}
+
+/// Move the cells stored before the whiteboard had sheets to a new sheet, returned if there were
+/// any. Without a sheet they can't be reached anymore.
+pub async fn migrate_legacy_cells(handle: &Handle) -> Result<Option<Sheet>, mongodb::error::Error> {
+    // Matches the documents without a `sheet` field as well
+    let legacy = doc! { "sheet": { "$in": [Bson::Null, ""] } };
+    if handle.canvas.count_documents(legacy.clone(), None).await? == 0 {
+        return Ok(None);
+    }
+    let sheet = create_sheet(handle, LEGACY_SHEET_NAME.to_string()).await?;
+    for collection in [&handle.canvas, &handle.history] {
+        collection
+            .update_many(
+                legacy.clone(),
+                doc! { "$set": { "sheet": &sheet.id } },
+                None,
+            )
+            .await?;
+    }
+    Ok(Some(sheet))
+}
+
+#[actix_web::test]
+async fn test_legacy_cells_migration() {
+    use crate::models::GridValue;
+    use mongodb::bson::{to_document, Document};
+
+    let handle = crate::database::create_handle().await;
+    let mut legacy = to_document(&GridValue {
+        user: "alice".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    legacy.remove("sheet");
+    let canvas = handle.db.collection::<Document>(handle.canvas.name());
+    canvas.insert_one(legacy, None).await.unwrap();
+
+    let sheet = migrate_legacy_cells(&handle).await.unwrap().unwrap();
+    assert_eq!(sheet.name, LEGACY_SHEET_NAME);
+    let cells = crate::database::get_grid(&handle, &sheet.id, &Default::default())
+        .await
+        .unwrap();
+    assert!(cells.iter().any(|cell| cell.user == "alice"));
+    assert!(migrate_legacy_cells(&handle).await.unwrap().is_none());
+    assert!(delete_sheet(&handle, &sheet.id).await.unwrap());
+}
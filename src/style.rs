@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson, Bson};
+
+use crate::block;
+use crate::database::{self, Handle};
+use crate::models::{Lines, Position, Style, StyleChange, StyleTarget, StyledTarget, Viewport};
+
+/// Largest number of targets styled at once
+const MAX_STYLE_TARGETS: usize = 10_000;
+/// Longest number format accepted
+const MAX_NUMBER_FORMAT_LENGTH: usize = 64;
+/// Widest column accepted, in pixels
+const MAX_WIDTH: u32 = 4096;
+
+fn is_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn in_sheet(target: &StyleTarget) -> bool {
+    match target {
+        StyleTarget::Cell(position) => block::in_sheet(position),
+        StyleTarget::Row(row) => block::in_sheet(&Position {
+            column: 0,
+            row: *row,
+        }),
+        StyleTarget::Column(column) => block::in_sheet(&Position {
+            column: *column,
+            row: 0,
+        }),
+    }
+}
+
+/// Check the change before applying it, returning the reason it is rejected
+pub fn validate(change: &StyleChange) -> Result<(), String> {
+    let Style {
+        color,
+        background,
+        number_format,
+        width,
+        ..
+    } = &change.style;
+    if change.targets.is_empty() {
+        return Err("no target to style".to_string());
+    }
+    if change.targets.len() > MAX_STYLE_TARGETS {
+        return Err(format!(
+            "at most {MAX_STYLE_TARGETS} targets can be styled at once"
+        ));
+    }
+    if !change.targets.iter().all(in_sheet) {
+        return Err("the target doesn't fit in the sheet".to_string());
+    }
+    if let Some(color) = [color, background]
+        .into_iter()
+        .flatten()
+        .find(|c| !is_color(c))
+    {
+        return Err(format!("invalid color {color}, expected #rrggbb"));
+    }
+    if number_format
+        .as_ref()
+        .is_some_and(|format| format.is_empty() || format.len() > MAX_NUMBER_FORMAT_LENGTH)
+    {
+        return Err("invalid number format".to_string());
+    }
+    if let Some(width) = width {
+        if !(1..=MAX_WIDTH).contains(width) {
+            return Err(format!("the width must be between 1 and {MAX_WIDTH}"));
+        }
+        if change
+            .targets
+            .iter()
+            .any(|target| !matches!(target, StyleTarget::Column(_)))
+        {
+            return Err("only columns have a width".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Styles of the cells, rows and columns inside the viewport
+pub async fn get_styles(
+    handle: &Handle,
+    sheet_id: &str,
+    viewport: &Viewport,
+) -> Result<Vec<StyledTarget>, mongodb::error::Error> {
+    let (rows, columns) = viewport.bounds();
+    let filter = doc! {
+        "sheet": sheet_id,
+        "$or": [
+            { "target.cell.row": rows.clone(), "target.cell.column": columns.clone() },
+            { "target.row": rows },
+            { "target.column": columns },
+        ],
+    };
+    handle.styles.find(filter, None).await?.try_collect().await
+}
+
+/// Apply a style to its targets, returning the resulting style of each of them.
+pub async fn apply_style(
+    handle: &Handle,
+    sheet_id: &str,
+    change: &StyleChange,
+) -> Result<Vec<StyledTarget>, mongodb::error::Error> {
+    let targets = change
+        .targets
+        .iter()
+        .map(to_bson)
+        .collect::<Result<Vec<Bson>, _>>()?;
+    let filter = doc! { "sheet": sheet_id, "target": { "$in": targets } };
+    let mut current: HashMap<StyleTarget, Style> = HashMap::new();
+    if !change.reset {
+        let stored: Vec<StyledTarget> = handle
+            .styles
+            .find(filter.clone(), None)
+            .await?
+            .try_collect()
+            .await?;
+        current.extend(
+            stored
+                .into_iter()
+                .map(|styled| (styled.target, styled.style)),
+        );
+    }
+
+    let mut applied: Vec<StyledTarget> = Vec::new();
+    for target in &change.targets {
+        if applied.iter().any(|styled| styled.target == *target) {
+            continue;
+        }
+        let mut style = current.remove(target).unwrap_or_default();
+        style.merge(&change.style);
+        applied.push(StyledTarget {
+            sheet: sheet_id.to_string(),
+            target: target.clone(),
+            style,
+        });
+    }
+
+    // Targets left without any style are not stored
+    let (stored, cleared): (Vec<&StyledTarget>, Vec<&StyledTarget>) =
+        applied.iter().partition(|styled| !styled.style.is_empty());
+    let stored = stored
+        .into_iter()
+        .map(|styled| {
+            let filter = doc! { "sheet": sheet_id, "target": to_bson(&styled.target)? };
+            Ok((filter, styled))
+        })
+        .collect::<Result<Vec<_>, mongodb::error::Error>>()?;
+    database::replace_many(handle, &handle.styles, stored).await?;
+    if !cleared.is_empty() {
+        let cleared = cleared
+            .iter()
+            .map(|styled| to_bson(&styled.target))
+            .collect::<Result<Vec<Bson>, _>>()?;
+        handle
+            .styles
+            .delete_many(
+                doc! { "sheet": sheet_id, "target": { "$in": cleared } },
+                None,
+            )
+            .await?;
+    }
+    Ok(applied)
+}
+
+/// Move the styles once rows or columns are inserted (deleted), dropping those of deleted lines
+pub async fn shift_styles(
+    handle: &Handle,
+    sheet_id: &str,
+    lines: &Lines,
+    inserted: bool,
+) -> Result<(), mongodb::error::Error> {
+    // Cells, then whole rows or columns along the axis
+    for field in [
+        format!("target.cell.{}", lines.field()),
+        format!("target.{}", lines.field()),
+    ] {
+        database::shift_field(&handle.styles, sheet_id, &field, lines, inserted).await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_style_validation() {
+    let change = |targets: Vec<StyleTarget>, style: Style| StyleChange {
+        targets,
+        style,
+        reset: false,
+    };
+    let column = vec![StyleTarget::Column(2)];
+    let colored = Style {
+        color: Some("#1F2937".to_string()),
+        bold: Some(true),
+        ..Default::default()
+    };
+    assert!(validate(&change(column.clone(), colored.clone())).is_ok());
+    assert!(validate(&change(vec![], colored.clone())).is_err());
+    let invalid = Style {
+        background: Some("red".to_string()),
+        ..Default::default()
+    };
+    assert!(validate(&change(column.clone(), invalid)).is_err());
+    let wide = Style {
+        width: Some(120),
+        ..Default::default()
+    };
+    assert!(validate(&change(column, wide.clone())).is_ok());
+    assert!(validate(&change(vec![StyleTarget::Row(0)], wide)).is_err());
+    let outside = vec![StyleTarget::Row(u64::MAX)];
+    assert!(validate(&change(outside, colored.clone())).is_err());
+    let many = vec![StyleTarget::Column(0); MAX_STYLE_TARGETS + 1];
+    assert!(validate(&change(many, colored.clone())).is_err());
+
+    let mut style = colored;
+    style.merge(&Style {
+        bold: Some(false),
+        ..Default::default()
+    });
+    assert_eq!(style.bold, Some(false));
+    assert_eq!(style.color.as_deref(), Some("#1F2937"));
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use sqlx::{postgres::PgPool, Postgres, Transaction};
+
+use crate::{
+    introspection::{self, ColumnInfo, TableName},
+    models::{CellUpdate, NewRow},
+    query::{self, key_parameters, key_value, to_text_parameter, BytesRow, QueryError},
+};
+
+fn find_column<'a>(
+    columns: &'a [ColumnInfo],
+    column_name: &str,
+) -> Result<&'a ColumnInfo, QueryError> {
+    columns
+        .iter()
+        .find(|c| c.column_name == column_name)
+        .ok_or_else(|| QueryError::Invalid(format!("unknown column {column_name}")))
+}
+
+/// Primary key of a table that can be edited, tables without primary key are read-only
+fn editable_primary_key(columns: &[ColumnInfo]) -> Result<Vec<&ColumnInfo>, QueryError> {
+    let primary_key = introspection::primary_key(columns);
+    if primary_key.is_empty() {
+        return Err(QueryError::Invalid(
+            "the table has no primary key, it is read-only".to_string(),
+        ));
+    }
+    Ok(primary_key)
+}
+
+/// Condition matching the row identified by `key`, its values are bound after the existing `parameters`
+fn key_condition(
+    primary_key: &[&ColumnInfo],
+    key: &serde_json::Value,
+    parameters: &mut Vec<Option<String>>,
+) -> Result<String, QueryError> {
+    let conditions: Vec<String> = primary_key
+        .iter()
+        .zip(key_parameters(primary_key, key)?)
+        .map(|(column, value)| {
+            parameters.push(value);
+            format!(
+                "{} = ${}::{}",
+                column.quoted_name(),
+                parameters.len(),
+                column.cast_type()
+            )
+        })
+        .collect();
+    Ok(format!("({})", conditions.join(" AND ")))
+}
+
+/// Start a transaction whose writes are not notified by the change triggers, as their changes
+/// are broadcasted by the sessions making them
+async fn begin_write(pool: &PgPool) -> Result<Transaction<'_, Postgres>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query("SET LOCAL ferrixcel.origin = 'ferrixcel';")
+        .execute(&mut *transaction)
+        .await?;
+    Ok(transaction)
+}
+
+/// Text representation of the values of a row by column, as printed by Postgres, which can be
+/// cast back to the column types without loss
+pub type RowText = HashMap<String, Option<String>>;
+
+/// Columns selected with their text representation, after the columns of the row
+fn text_columns(columns: &[ColumnInfo]) -> String {
+    columns
+        .iter()
+        .map(|column| format!("{}::text", column.quoted_name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Split a row selected with `text_columns` into the row and the text of its values
+fn split_text(columns: &[ColumnInfo], mut raw_row: BytesRow) -> (BytesRow, Vec<Option<String>>) {
+    let text = raw_row
+        .split_off(columns.len())
+        .iter()
+        .map(|value| {
+            value
+                .as_ref()
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        })
+        .collect();
+    (raw_row, text)
+}
+
+/// Row with its primary key and the values needed to insert it again
+#[derive(Debug, Clone)]
+pub struct KeyedRow {
+    pub primary_key: serde_json::Value,
+    pub row: Vec<serde_json::Value>,
+    /// Values to restore the row, generated columns and identity columns outside of the
+    /// primary key being left to the database
+    pub values: RowText,
+}
+
+fn keyed_row(
+    columns: &[ColumnInfo],
+    row: Vec<serde_json::Value>,
+    text: Vec<Option<String>>,
+) -> KeyedRow {
+    let primary_key = introspection::primary_key(columns)
+        .iter()
+        .map(|key_column| {
+            columns
+                .iter()
+                .position(|c| c.column_name == key_column.column_name)
+                .map(|index| row[index].clone())
+                .expect("primary key columns come from the same list")
+        })
+        .collect();
+    let values = columns
+        .iter()
+        .zip(text)
+        .filter(|(column, _)| {
+            !column.is_generated && (!column.is_identity || column.is_primary_key)
+        })
+        .map(|(column, value)| (column.column_name.clone(), value))
+        .collect();
+    KeyedRow {
+        primary_key: key_value(primary_key),
+        row,
+        values,
+    }
+}
+
+/// Fetch a single row by primary key, `None` if it doesn't exist (anymore).
+/// `columns` are the columns of the table, as listed by `introspection::list_columns`.
+pub async fn fetch_row(
+    pool: &PgPool,
+    table_name: &TableName,
+    columns: &[ColumnInfo],
+    key: &serde_json::Value,
+) -> Result<Option<Vec<serde_json::Value>>, QueryError> {
+    let primary_key = editable_primary_key(columns)?;
+
+    let mut parameters = Vec::new();
+    let condition = key_condition(&primary_key, key, &mut parameters)?;
+    let sql = format!("SELECT * FROM {} WHERE {condition};", table_name.quoted());
+    let mut query = sqlx::query_as::<Postgres, BytesRow>(&sql);
+    for parameter in parameters {
+        query = query.bind(parameter);
+    }
+    let raw_row = query.fetch_optional(pool).await?;
+
+    Ok(raw_row.and_then(|raw_row| query::parse_rows(columns.to_vec(), vec![raw_row]).pop()))
+}
+
+/// Cell updated by `update_cell` or `restore_cell`
+#[derive(Debug, Clone)]
+pub struct UpdatedCell {
+    /// Text representation of the value before the update
+    pub before: Option<String>,
+    /// Text representation of the value committed by the database
+    pub after: Option<String>,
+    pub committed: CellUpdate,
+}
+
+/// Update one cell of a row identified by its primary key,
+/// returning the previous value and the value committed by the database.
+pub async fn update_cell(
+    pool: &PgPool,
+    table_name: &TableName,
+    update: CellUpdate,
+) -> Result<UpdatedCell, QueryError> {
+    let value = to_text_parameter(&update.value);
+    restore_cell(pool, table_name, update.primary_key, update.column, value).await
+}
+
+/// Write the text representation of a value in a cell, as returned in `UpdatedCell`
+pub async fn restore_cell(
+    pool: &PgPool,
+    table_name: &TableName,
+    primary_key_value: serde_json::Value,
+    column_name: String,
+    value: Option<String>,
+) -> Result<UpdatedCell, QueryError> {
+    let columns = introspection::list_columns(pool, table_name).await?;
+    let primary_key = editable_primary_key(&columns)?;
+    let column = find_column(&columns, &column_name)?;
+    let column_index = columns
+        .iter()
+        .position(|c| c.column_name == column.column_name)
+        .expect("column comes from the same list");
+
+    let mut transaction = begin_write(pool).await?;
+    let mut parameters = Vec::new();
+    let condition = key_condition(&primary_key, &primary_key_value, &mut parameters)?;
+    let sql = format!(
+        "SELECT {}::text FROM {} WHERE {condition} FOR UPDATE;",
+        column.quoted_name(),
+        table_name.quoted()
+    );
+    let mut query = sqlx::query_scalar::<Postgres, Option<String>>(&sql);
+    for parameter in parameters {
+        query = query.bind(parameter);
+    }
+    let before = query.fetch_one(&mut *transaction).await?;
+
+    let mut parameters = vec![value];
+    let condition = key_condition(&primary_key, &primary_key_value, &mut parameters)?;
+    let sql = format!(
+        "UPDATE {} SET {} = $1::{} WHERE {condition} RETURNING *, {}::text;",
+        table_name.quoted(),
+        column.quoted_name(),
+        column.cast_type(),
+        column.quoted_name()
+    );
+    let mut query = sqlx::query_as::<Postgres, BytesRow>(&sql);
+    for parameter in parameters {
+        query = query.bind(parameter);
+    }
+    let raw_row = query.fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+
+    let (raw_row, mut text) = split_text(&columns, raw_row);
+    let mut row = query::parse_rows(columns.clone(), vec![raw_row])
+        .pop()
+        .expect("one row was parsed");
+    Ok(UpdatedCell {
+        before,
+        after: text.pop().expect("the text of the cell was selected"),
+        committed: CellUpdate {
+            primary_key: primary_key_value,
+            column: column_name,
+            value: row.swap_remove(column_index),
+        },
+    })
+}
+
+/// Insert a row, columns without a value use their default, returning the inserted row.
+pub async fn insert_row(
+    pool: &PgPool,
+    table_name: &TableName,
+    new_row: NewRow,
+) -> Result<KeyedRow, QueryError> {
+    let values = new_row
+        .values
+        .iter()
+        .map(|(name, value)| (name.clone(), to_text_parameter(value)))
+        .collect();
+    insert_values(pool, table_name, values, false).await
+}
+
+/// Insert again a row returned in `KeyedRow`, with the same primary key
+pub async fn restore_row(
+    pool: &PgPool,
+    table_name: &TableName,
+    values: RowText,
+) -> Result<KeyedRow, QueryError> {
+    insert_values(pool, table_name, values, true).await
+}
+
+/// Insert a row from the text representation of its values. With `overriding`, the values
+/// given for identity columns replace the ones of their sequence.
+async fn insert_values(
+    pool: &PgPool,
+    table_name: &TableName,
+    values: RowText,
+    overriding: bool,
+) -> Result<KeyedRow, QueryError> {
+    let columns = introspection::list_columns(pool, table_name).await?;
+    editable_primary_key(&columns)?;
+    if let Some(unknown) = values
+        .keys()
+        .find(|name| !columns.iter().any(|c| &&c.column_name == name))
+    {
+        return Err(QueryError::Invalid(format!("unknown column {unknown}")));
+    }
+
+    let mut names = Vec::new();
+    let mut placeholders = Vec::new();
+    let mut parameters = Vec::new();
+    for column in &columns {
+        if let Some(value) = values.get(&column.column_name) {
+            parameters.push(value.clone());
+            placeholders.push(format!("${}::{}", parameters.len(), column.cast_type()));
+        } else if column.column_default.is_some() {
+            placeholders.push("DEFAULT".to_string());
+        } else {
+            continue;
+        }
+        names.push(column.quoted_name());
+    }
+    let sql = if names.is_empty() {
+        format!(
+            "INSERT INTO {} DEFAULT VALUES RETURNING *, {};",
+            table_name.quoted(),
+            text_columns(&columns)
+        )
+    } else {
+        format!(
+            "INSERT INTO {} ({}) {}VALUES ({}) RETURNING *, {};",
+            table_name.quoted(),
+            names.join(", "),
+            if overriding {
+                "OVERRIDING SYSTEM VALUE "
+            } else {
+                ""
+            },
+            placeholders.join(", "),
+            text_columns(&columns)
+        )
+    };
+
+    let mut query = sqlx::query_as::<Postgres, BytesRow>(&sql);
+    for parameter in parameters {
+        query = query.bind(parameter);
+    }
+    let mut transaction = begin_write(pool).await?;
+    let raw_row = query.fetch_one(&mut *transaction).await?;
+    transaction.commit().await?;
+
+    let (raw_row, text) = split_text(&columns, raw_row);
+    let row = query::parse_rows(columns.clone(), vec![raw_row])
+        .pop()
+        .expect("one row was returned");
+    Ok(keyed_row(&columns, row, text))
+}
+
+/// Delete the rows matching the given primary keys, returning the rows that were deleted.
+pub async fn delete_rows(
+    pool: &PgPool,
+    table_name: &TableName,
+    keys: Vec<serde_json::Value>,
+) -> Result<Vec<KeyedRow>, QueryError> {
+    let columns = introspection::list_columns(pool, table_name).await?;
+    let primary_key = editable_primary_key(&columns)?;
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut parameters = Vec::new();
+    let conditions = keys
+        .iter()
+        .map(|key| key_condition(&primary_key, key, &mut parameters))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sql = format!(
+        "DELETE FROM {} WHERE {} RETURNING *, {};",
+        table_name.quoted(),
+        conditions.join(" OR "),
+        text_columns(&columns)
+    );
+    let mut query = sqlx::query_as::<Postgres, BytesRow>(&sql);
+    for parameter in parameters {
+        query = query.bind(parameter);
+    }
+    let mut transaction = begin_write(pool).await?;
+    let raw_rows = query.fetch_all(&mut *transaction).await?;
+    transaction.commit().await?;
+
+    let (raw_rows, texts): (Vec<_>, Vec<_>) = raw_rows
+        .into_iter()
+        .map(|raw_row| split_text(&columns, raw_row))
+        .unzip();
+    Ok(query::parse_rows(columns.clone(), raw_rows)
+        .into_iter()
+        .zip(texts)
+        .map(|(row, text)| keyed_row(&columns, row, text))
+        .collect())
+}
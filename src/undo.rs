@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+
+use sqlx::postgres::PgPool;
+
+use crate::{
+    database::Handle,
+    grid,
+    introspection::TableName,
+    models::{ActionKind, CellValue, Lines, NewGridValue, Position},
+    query::QueryError,
+    table::{self, RowText},
+    websocket::Room,
+};
+
+/// Number of edits kept for each user and room
+const MAX_EDITS: usize = 100;
+/// Cells and rows kept in the edits of each stack
+const MAX_EDITED_CELLS: usize = 200_000;
+
+/// Edit made by a user, with what is needed to revert and replay it
+#[derive(Debug, Clone)]
+pub enum Edit {
+    GridValue {
+        position: Position,
+        before: Option<CellValue>,
+        after: Option<CellValue>,
+    },
+    /// Cell of a table, with the text representations of its values
+    Cell {
+        primary_key: serde_json::Value,
+        column: String,
+        before: Option<String>,
+        after: Option<String>,
+    },
+    RowInserted {
+        primary_key: serde_json::Value,
+        values: RowText,
+    },
+    RowsDeleted(Vec<(serde_json::Value, RowText)>),
+    /// Cells written at once by a paste or a fill, with their content before and after
+    GridValues(Vec<(Position, Option<CellValue>, Option<CellValue>)>),
+}
+
+impl Edit {
+    /// Grid positions that must be locked by the user to revert or replay the edit. Table edits
+    /// don't need any, as they are made without locks: their rows are identified by primary key,
+    /// not by a position the users could lock.
+    pub fn locked_positions(&self) -> Vec<&Position> {
+        match self {
+            Edit::GridValue { position, .. } => vec![position],
+            Edit::GridValues(cells) => cells.iter().map(|(position, _, _)| position).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Number of cells or rows held by the edit
+    fn size(&self) -> usize {
+        match self {
+            Edit::GridValue { .. } | Edit::Cell { .. } | Edit::RowInserted { .. } => 1,
+            Edit::GridValues(cells) => cells.len(),
+            Edit::RowsDeleted(rows) => rows.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Undo,
+    Redo,
+}
+
+#[derive(Debug, Default)]
+struct Stacks {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl Stacks {
+    fn stack(&mut self, direction: Direction) -> &mut Vec<Edit> {
+        match direction {
+            Direction::Undo => &mut self.undo,
+            Direction::Redo => &mut self.redo,
+        }
+    }
+
+    fn push(&mut self, direction: Direction, edit: Edit) {
+        let stack = self.stack(direction);
+        stack.push(edit);
+        // The oldest edits are dropped first, the last one being kept whatever its size
+        let mut size: usize = stack.iter().map(Edit::size).sum();
+        while stack.len() > MAX_EDITS || (stack.len() > 1 && size > MAX_EDITED_CELLS) {
+            size -= stack.remove(0).size();
+        }
+    }
+}
+
+lazy_static! {
+    /// Undo and redo stacks of each user in each room
+    static ref STACKS: RwLock<HashMap<(String, Room), Stacks>> = RwLock::new(HashMap::new());
+}
+
+fn with_stacks<T>(username: &str, room: &Room, f: impl FnOnce(&mut Stacks) -> T) -> T {
+    let mut stacks = STACKS.write().expect("write in undo stacks");
+    f(stacks
+        .entry((username.to_string(), room.clone()))
+        .or_default())
+}
+
+/// Record a new edit, what was undone before can't be redone anymore
+pub fn record(username: &str, room: &Room, edit: Edit) {
+    with_stacks(username, room, |stacks| {
+        stacks.push(Direction::Undo, edit);
+        stacks.redo.clear();
+    });
+}
+
+/// Take the edit to undo or redo
+pub fn take(username: &str, room: &Room, direction: Direction) -> Option<Edit> {
+    with_stacks(username, room, |stacks| stacks.stack(direction).pop())
+}
+
+/// Give back an edit that couldn't be undone or redone
+pub fn restore(username: &str, room: &Room, direction: Direction, edit: Edit) {
+    with_stacks(username, room, |stacks| stacks.push(direction, edit));
+}
+
+/// Move an edit that was undone (redone) to the redo (undo) stack
+pub fn complete(username: &str, room: &Room, direction: Direction, edit: Edit) {
+    let opposite = match direction {
+        Direction::Undo => Direction::Redo,
+        Direction::Redo => Direction::Undo,
+    };
+    with_stacks(username, room, |stacks| stacks.push(opposite, edit));
+}
+
+/// Drop the edits of a user who left the room
+pub fn forget(username: &str, room: &Room) {
+    STACKS
+        .write()
+        .expect("write in undo stacks")
+        .remove(&(username.to_string(), room.clone()));
+}
+
+/// Move the grid edits of every user in the room once rows or columns are inserted (deleted),
+/// the edits of deleted cells being dropped
+pub fn shift_edits(room: &Room, lines: &Lines, inserted: bool) {
+    let mut stacks = STACKS.write().expect("write in undo stacks");
+    let shift = |edits: &mut Vec<Edit>| {
+        edits.retain_mut(|edit| match edit {
+            Edit::GridValue { position, .. } => match lines.shift_position(position, inserted) {
+                Some(shifted) => {
+                    *position = shifted;
+                    true
+                }
+                None => false,
+            },
+            Edit::GridValues(cells) => {
+                cells.retain_mut(|(position, _, _)| {
+                    match lines.shift_position(position, inserted) {
+                        Some(shifted) => {
+                            *position = shifted;
+                            true
+                        }
+                        None => false,
+                    }
+                });
+                !cells.is_empty()
+            }
+            _ => true,
+        })
+    };
+    for ((_, edited_room), stacks) in stacks.iter_mut() {
+        if edited_room == room {
+            shift(&mut stacks.undo);
+            shift(&mut stacks.redo);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UndoError {
+    Grid(mongodb::error::Error),
+    Table(QueryError),
+}
+
+impl fmt::Display for UndoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndoError::Grid(err) => write!(f, "{err}"),
+            UndoError::Table(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for UndoError {
+    fn from(err: mongodb::error::Error) -> Self {
+        UndoError::Grid(err)
+    }
+}
+
+impl From<QueryError> for UndoError {
+    fn from(err: QueryError) -> Self {
+        UndoError::Table(err)
+    }
+}
+
+fn table_of(room: &Room) -> Result<&TableName, UndoError> {
+    match room {
+        Room::Table(table_name) => Ok(table_name),
+        Room::Sheet(_) => Err(UndoError::Table(QueryError::Invalid(
+            "rows can only be edited on a table session".to_string(),
+        ))),
+    }
+}
+
+/// Revert or replay an edit, returning the actions to broadcast. Grid edits fail if rows or
+/// columns were inserted or deleted since `generation`.
+pub async fn apply(
+    pool: &PgPool,
+    handle: &Handle,
+    room: &Room,
+    username: String,
+    edit: &Edit,
+    direction: Direction,
+    generation: Option<u64>,
+) -> Result<Vec<ActionKind>, UndoError> {
+    let undo = direction == Direction::Undo;
+    match edit {
+        Edit::GridValue {
+            position,
+            before,
+            after,
+        } => {
+            let Room::Sheet(sheet_id) = room else {
+                return Err(UndoError::Table(QueryError::Invalid(
+                    "grid values can only be edited on a whiteboard session".to_string(),
+                )));
+            };
+            let new_box = NewGridValue {
+                position: position.clone(),
+                value: if undo { before } else { after }.clone(),
+                formula: None,
+            };
+            let changed = grid::set_value(handle, sheet_id, new_box, username, generation).await?;
+            Ok(changed.into_iter().map(ActionKind::NewGridValue).collect())
+        }
+        Edit::GridValues(cells) => {
+            let Room::Sheet(sheet_id) = room else {
+                return Err(UndoError::Table(QueryError::Invalid(
+                    "grid values can only be edited on a whiteboard session".to_string(),
+                )));
+            };
+            let new_boxes = cells
+                .iter()
+                .map(|(position, before, after)| NewGridValue {
+                    position: position.clone(),
+                    value: if undo { before } else { after }.clone(),
+                    formula: None,
+                })
+                .collect();
+            let changed =
+                grid::set_values(handle, sheet_id, new_boxes, username, generation).await?;
+            Ok(vec![ActionKind::NewGridValues(changed)])
+        }
+        Edit::Cell {
+            primary_key,
+            column,
+            before,
+            after,
+        } => {
+            let updated = table::restore_cell(
+                pool,
+                table_of(room)?,
+                primary_key.clone(),
+                column.clone(),
+                if undo { before } else { after }.clone(),
+            )
+            .await?;
+            Ok(vec![ActionKind::UpdateCell(updated.committed)])
+        }
+        Edit::RowInserted {
+            primary_key,
+            values,
+        } => {
+            if undo {
+                delete(pool, table_of(room)?, vec![primary_key.clone()]).await
+            } else {
+                insert(pool, table_of(room)?, vec![values.clone()]).await
+            }
+        }
+        Edit::RowsDeleted(rows) => {
+            if undo {
+                let values = rows.iter().map(|(_, values)| values.clone()).collect();
+                insert(pool, table_of(room)?, values).await
+            } else {
+                let keys = rows.iter().map(|(key, _)| key.clone()).collect();
+                delete(pool, table_of(room)?, keys).await
+            }
+        }
+    }
+}
+
+async fn insert(
+    pool: &PgPool,
+    table_name: &TableName,
+    rows: Vec<RowText>,
+) -> Result<Vec<ActionKind>, UndoError> {
+    let mut actions = Vec::new();
+    for values in rows {
+        let inserted = table::restore_row(pool, table_name, values).await?;
+        actions.push(ActionKind::RowInserted(inserted.row));
+    }
+    Ok(actions)
+}
+
+async fn delete(
+    pool: &PgPool,
+    table_name: &TableName,
+    keys: Vec<serde_json::Value>,
+) -> Result<Vec<ActionKind>, UndoError> {
+    let deleted = table::delete_rows(pool, table_name, keys).await?;
+    Ok(vec![ActionKind::DeleteRows(
+        deleted.into_iter().map(|row| row.primary_key).collect(),
+    )])
+}
+
+#[test]
+fn test_undo_stacks() {
+    let room = Room::Sheet("test_undo_stacks".to_string());
+    let edit = |after: f64| Edit::GridValue {
+        position: Position::default(),
+        before: None,
+        after: Some(CellValue::Number(after)),
+    };
+    let after = |edit: Option<Edit>| match edit {
+        Some(Edit::GridValue { after, .. }) => after,
+        other => panic!("unexpected edit {other:?}"),
+    };
+
+    record("alice", &room, edit(1.));
+    record("alice", &room, edit(2.));
+    let undone = take("alice", &room, Direction::Undo);
+    assert_eq!(after(undone.clone()), Some(CellValue::Number(2.)));
+    complete("alice", &room, Direction::Undo, undone.unwrap());
+    assert!(take("bob", &room, Direction::Undo).is_none());
+
+    // A new edit drops what could be redone
+    record("alice", &room, edit(3.));
+    assert!(take("alice", &room, Direction::Redo).is_none());
+    assert_eq!(
+        after(take("alice", &room, Direction::Undo)),
+        Some(CellValue::Number(3.))
+    );
+    assert_eq!(
+        after(take("alice", &room, Direction::Undo)),
+        Some(CellValue::Number(1.))
+    );
+}
+
+#[test]
+fn test_undo_stacks_size() {
+    let room = Room::Sheet("test_undo_stacks_size".to_string());
+    let paste = || {
+        Edit::GridValues(vec![
+            (Position::default(), None, None);
+            MAX_EDITED_CELLS / 2
+        ])
+    };
+    record("alice", &room, paste());
+    record("alice", &room, paste());
+    record("alice", &room, paste());
+    let size = |direction| {
+        with_stacks("alice", &room, |stacks: &mut Stacks| {
+            stacks.stack(direction).len()
+        })
+    };
+    assert_eq!(size(Direction::Undo), 2);
+
+    forget("alice", &room);
+    assert!(!STACKS
+        .read()
+        .unwrap()
+        .contains_key(&("alice".to_string(), room.clone())));
+    assert!(take("alice", &room, Direction::Undo).is_none());
+}
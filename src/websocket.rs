@@ -1,26 +1,128 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    env,
+    future::Future,
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
 use actix::prelude::*;
 
 use actix_web_actors::ws;
-use log::{debug, info};
+use log::{debug, error, info};
 use mongodb::bson::Uuid;
 use serde::Serialize;
+use sqlx::PgPool;
 
 use crate::{
+    block,
+    database::Handle,
     grid,
-    models::{ActionKind, Broadcast, Position},
+    introspection::TableName,
+    models::{
+        ActionKind, Broadcast, Lines, NewGridValue, Position, Session, StyleTarget, Viewport,
+    },
+    style, table,
+    undo::{self, Direction, Edit, UndoError},
 };
 
-type Users = Arc<RwLock<HashMap<Uuid, (Addr<MyWs>, MyWs)>>>;
-type Selections = Arc<RwLock<HashMap<Position, String>>>;
+type Selections = Arc<RwLock<HashMap<Room, HashMap<Position, Lock>>>>;
+type Rooms = Arc<RwLock<HashMap<Room, HashMap<Uuid, Member>>>>;
+type Dropped = Arc<RwLock<HashMap<String, DroppedSession>>>;
 
 lazy_static! {
-    pub static ref USERS: Users = Arc::new(RwLock::new(HashMap::new()));
+    /// Sessions connected to each room, broadcasts only reach the sessions of the same room
+    static ref ROOMS: Rooms = Arc::new(RwLock::new(HashMap::new()));
     pub static ref SELECTIONS: Selections = Arc::new(RwLock::new(HashMap::new()));
+    /// Time after which the locks of an inactive user are released
+    static ref LOCK_TTL: Duration = Duration::from_secs(
+        env::var("LOCK_TTL_SECONDS")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(DEFAULT_LOCK_TTL_SECONDS)
+    );
+    /// Sessions whose connection dropped by resume token, until they are resumed or expire
+    static ref DROPPED: Dropped = Arc::new(RwLock::new(HashMap::new()));
+    /// Time during which a dropped session keeps its locks and can be resumed
+    static ref SESSION_GRACE: Duration = Duration::from_secs(
+        env::var("SESSION_GRACE_SECONDS")
+            .ok()
+            .and_then(|grace| grace.parse().ok())
+            .unwrap_or(DEFAULT_SESSION_GRACE_SECONDS)
+    );
+}
+
+const DEFAULT_LOCK_TTL_SECONDS: u64 = 300;
+const DEFAULT_SESSION_GRACE_SECONDS: u64 = 30;
+/// Broadcasts kept for a dropped session, the client reloads the whiteboard beyond that
+const MAX_MISSED_BROADCASTS: usize = 1000;
+
+/// How often the server pings the client
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Time without any frame from the client after which the session is considered dead
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Grid position selected by a user, refreshed by each action of the user in the room
+#[derive(Debug, Clone)]
+pub struct Lock {
+    pub owner: String,
+    pub refreshed: Instant,
+}
+
+impl Lock {
+    fn new(owner: String) -> Self {
+        Lock {
+            owner,
+            refreshed: Instant::now(),
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.refreshed) > *LOCK_TTL
+    }
+}
+
+/// Session connected to a room
+struct Member {
+    addr: Addr<MyWs>,
+    username: String,
+    /// Resume token of the session, to take it over if the client reconnects before its
+    /// connection is known to be dead
+    token: String,
+}
+
+/// Session whose connection dropped without closing, its locks are kept during the grace period
+#[derive(Debug)]
+struct DroppedSession {
+    room: Room,
+    username: String,
+    viewport: Viewport,
+    dropped: Instant,
+    /// Broadcasts to the room since the connection dropped, replayed on resume
+    missed: Vec<String>,
+    /// Whether broadcasts were discarded once `MAX_MISSED_BROADCASTS` was reached
+    overflowed: bool,
+}
+
+impl DroppedSession {
+    fn miss(&mut self, message: String) {
+        if self.overflowed {
+            return;
+        }
+        if self.missed.len() == MAX_MISSED_BROADCASTS {
+            self.missed.clear();
+            self.overflowed = true;
+            return;
+        }
+        self.missed.push(message);
+    }
+}
+
+/// What a session is connected to, selections and broadcasts are scoped by room
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Room {
+    Sheet(String),
+    Table(TableName),
 }
 
 #[derive(Clone)]
@@ -28,25 +130,94 @@ pub struct MyWs {
     pub uuid: Uuid,
     pub username: String,
     pub ip: String,
-    pub table: Option<String>,
+    pub table: Option<TableName>,
+    /// Id of the whiteboard sheet, for sessions that are not connected to a table
+    pub sheet: Option<String>,
+    /// Only the grid values inside the viewport are sent to the session
+    pub viewport: Viewport,
+    /// Last time a frame was received from the client
+    pub heartbeat: Instant,
+    /// Secret given to the client to resume the session after a network drop
+    pub token: String,
+    /// Token of the dropped session to resume when starting
+    pub resume: Option<String>,
+    /// Whether the session was closed on purpose, its locks being released at once
+    pub closed: bool,
+    pub pool: PgPool,
+    pub handle: Handle,
 }
 
 impl Actor for MyWs {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("User connection: [{}] -> {}", self.ip, self.username);
-        let mut users = USERS.write().expect("unable to get lock on users");
-
-        users.insert(self.uuid, (ctx.address(), self.clone()));
+        self.start_heartbeat(ctx);
+        let resumed = {
+            let mut rooms = ROOMS.write().expect("unable to get lock on rooms");
+            let sessions = rooms.entry(self.room()).or_default();
+            // Taken while no broadcast is sent so that none is missed or received twice
+            let resumed = self.resume.take().and_then(|token| {
+                let dropped = resume_session(&token, &self.room(), &self.username)
+                    .or_else(|| take_over(sessions, &token, &self.room(), &self.username))?;
+                Some((token, dropped))
+            });
+            if let Some((token, dropped)) = &resumed {
+                self.token = token.clone();
+                self.viewport = dropped.viewport.clone();
+            }
+            sessions.insert(
+                self.uuid,
+                Member {
+                    addr: ctx.address(),
+                    username: self.username.clone(),
+                    token: self.token.clone(),
+                },
+            );
+            resumed.map(|(_, dropped)| dropped)
+        };
+        let (session, missed) = match resumed {
+            Some(dropped) => {
+                info!("Resumed session of [{}] -> {}", self.ip, self.username);
+                let session = Session {
+                    token: self.token.clone(),
+                    resumed: true,
+                    complete: !dropped.overflowed,
+                };
+                (session, dropped.missed)
+            }
+            None => {
+                let session = Session {
+                    token: self.token.clone(),
+                    resumed: false,
+                    complete: true,
+                };
+                (session, Vec::new())
+            }
+        };
+        // Written before any message of the mailbox, the missed broadcasts staying in order
+        let action = ActionKind::Session(session);
+        ctx.text(
+            serde_json::to_string(&Broadcast {
+                who: &self.username,
+                kind: action.as_ref(),
+                payload: action.get_action_payload(),
+            })
+            .unwrap(),
+        );
+        for message in missed {
+            ctx.text(message);
+        }
         let selection_by_user = {
             let selected = SELECTIONS.read().unwrap();
             let mut selection_by_user: HashMap<String, Vec<Position>> = HashMap::new();
             selected
-                .clone()
+                .get(&self.room())
+                .cloned()
+                .unwrap_or_default()
                 .into_iter()
-                .for_each(|(position, username)| {
+                .for_each(|(position, lock)| {
                     selection_by_user
-                        .entry(username)
+                        .entry(lock.owner)
                         .and_modify(|positions| positions.push(position.clone()))
                         .or_insert(vec![position]);
                 });
@@ -66,19 +237,42 @@ impl Actor for MyWs {
 
     fn stopped(&mut self, _: &mut Self::Context) {
         info!("User disconnecting: [{}] -> {}", self.ip, self.username);
-        {
-            let mut selections = SELECTIONS
-                .write()
-                .expect("unable to get lock on selections");
-            let deselection: Vec<Position> = selections
-                .extract_if(|_pos, username| username == &self.username)
-                .map(|(position, _username)| position)
-                .collect();
-            self.broadcast(ActionKind::Deselect(deselection));
-        }
-        {
-            let mut users = USERS.write().expect("unable to get lock on users");
-            users.remove(&self.uuid);
+        let room = self.room();
+        let user_connected = {
+            let mut rooms = ROOMS.write().expect("unable to get lock on rooms");
+            let Some(sessions) = rooms.get_mut(&room) else {
+                return;
+            };
+            if sessions.remove(&self.uuid).is_none() {
+                // Taken over by a resumed session, which keeps the locks
+                return;
+            }
+            let user_connected = sessions
+                .values()
+                .any(|member| member.username == self.username);
+            if sessions.is_empty() {
+                rooms.remove(&room);
+            }
+            if !self.closed {
+                // Kept along with the locks until the client resumes it or the grace period ends
+                DROPPED.write().expect("write in dropped sessions").insert(
+                    self.token.clone(),
+                    DroppedSession {
+                        room: room.clone(),
+                        username: self.username.clone(),
+                        viewport: self.viewport.clone(),
+                        dropped: Instant::now(),
+                        missed: Vec::new(),
+                        overflowed: false,
+                    },
+                );
+            }
+            user_connected
+        };
+        // The locks and edits are shared by the sessions of the user in the room
+        if self.closed && !user_connected {
+            release_locks(&room, |_, lock| lock.owner == self.username);
+            undo::forget(&self.username, &room);
         }
     }
 }
@@ -91,29 +285,546 @@ impl ActionKind {
     fn get_action_payload(&self) -> serde_json::Value {
         match self {
             ActionKind::NewGridValue(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::Paste(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::Fill(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::NewGridValues(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::UpdateCell(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::InsertRow(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::DeleteRows(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::RowInserted(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::RowUpdated(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::Undo | ActionKind::Redo => serde_json::Value::Null,
+            ActionKind::ApplyStyle(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::StyleApplied(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::InsertLines(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::DeleteLines(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::Viewport(x) => serde_json::to_value(x).unwrap(),
             ActionKind::Select(x) => serde_json::to_value(x).unwrap(),
             ActionKind::Deselect(x) => serde_json::to_value(x).unwrap(),
+            ActionKind::Session(x) => serde_json::to_value(x).unwrap(),
         }
     }
 }
 
 impl MyWs {
-    fn broadcast(&self, action: ActionKind) {
-        let payload = SendMessage(
-            serde_json::to_string(&Broadcast {
-                who: &self.username,
-                kind: action.as_ref(),
-                payload: action.get_action_payload(),
+    fn room(&self) -> Room {
+        match (&self.table, &self.sheet) {
+            (Some(table_name), _) => Room::Table(table_name.clone()),
+            (None, sheet) => Room::Sheet(sheet.clone().unwrap_or_default()),
+        }
+    }
+
+    /// Ping the client regularly and stop the session once it stops answering, its locks being
+    /// kept during the grace period in `stopped`
+    fn start_heartbeat(&self, ctx: &mut <Self as Actor>::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if act.heartbeat.elapsed() > CLIENT_TIMEOUT {
+                info!(
+                    "No heartbeat from [{}] -> {}, disconnecting",
+                    act.ip, act.username
+                );
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    /// Whether the grid position is locked by this session's user
+    fn holds_lock(&self, position: &Position) -> bool {
+        self.holds_locks([position])
+    }
+
+    /// Whether every grid position is locked by this session's user
+    fn holds_locks<'a>(&self, positions: impl IntoIterator<Item = &'a Position>) -> bool {
+        let selections = SELECTIONS.read().expect("read in selections");
+        let selections = selections.get(&self.room());
+        positions.into_iter().all(|position| {
+            selections
+                .and_then(|selections| selections.get(position))
+                .is_some_and(|lock| lock.owner == self.username)
+        })
+    }
+
+    /// Revert or replay the last edit of the user in this room
+    fn replay(&self, ctx: &mut <Self as Actor>::Context, direction: Direction) {
+        let room = self.room();
+        let Some(edit) = undo::take(&self.username, &room, direction) else {
+            self.send_error(ctx, 400, "Nothing to undo or redo.");
+            return;
+        };
+        // Read before the locks are checked, a later insertion or deletion moving the positions
+        let generation = self.sheet.as_deref().map(grid::generation);
+        if !self.holds_locks(edit.locked_positions()) {
+            undo::restore(&self.username, &room, direction, edit);
+            self.send_error(ctx, 400, "This grid position is not locked by you.");
+            return;
+        }
+        let pool = self.pool.clone();
+        let handle = self.handle.clone();
+        let username = self.username.clone();
+        let future = async move {
+            let applied = undo::apply(
+                &pool, &handle, &room, username, &edit, direction, generation,
+            )
+            .await;
+            (room, edit, applied)
+        };
+        future
+            .into_actor(self)
+            .map(move |(room, edit, applied), act, ctx| match applied {
+                Ok(actions) => {
+                    undo::complete(&act.username, &room, direction, edit);
+                    for action in actions {
+                        act.broadcast(action);
+                    }
+                }
+                Err(err) => {
+                    debug!("Unable to {direction:?} edit {edit:?}: {err}");
+                    undo::restore(&act.username, &room, direction, edit);
+                    let error_code = match &err {
+                        UndoError::Grid(err) if err.get_custom::<grid::CellsMoved>().is_none() => {
+                            500
+                        }
+                        UndoError::Grid(_) | UndoError::Table(_) => 400,
+                    };
+                    act.send_error(ctx, error_code, &format!("Unable to {direction:?}: {err}"));
+                }
             })
-            .unwrap(),
-        );
-        let users = USERS.write().expect("unable to get lock on users");
-        for (_username, user) in users.iter() {
-            user.0.do_send(payload.clone());
+            .spawn(ctx);
+    }
+
+    /// Insert (delete) rows or columns of the sheet once its other writes are over, the selections
+    /// and edits being moved once the cells are
+    fn shift_lines(&self, ctx: &mut <Self as Actor>::Context, lines: Lines, inserted: bool) {
+        let Some(sheet_id) = self.sheet.clone() else {
+            self.send_error(
+                ctx,
+                400,
+                "Rows and columns can only be inserted or deleted on a whiteboard session.",
+            );
+            return;
+        };
+        // Positions are stored as signed 64 bits integers
+        if lines.count == 0 || lines.index.saturating_add(lines.count) > i64::MAX as u64 {
+            self.send_error(ctx, 400, "Invalid rows or columns.");
+            return;
+        }
+        let room = self.room();
+        let handle = self.handle.clone();
+        let username = self.username.clone();
+        let future = async move {
+            let guard = grid::lock_sheet(&sheet_id).await;
+            if !inserted && locked_in_lines(&room, &lines, &username) {
+                return (room, lines, Ok(None));
+            }
+            let shifted =
+                grid::shift_lines(&handle, &guard, &sheet_id, &lines, inserted, username).await;
+            if shifted.is_ok() {
+                shift_selections(&room, &lines, inserted);
+                undo::shift_edits(&room, &lines, inserted);
+            }
+            // Even partly moved, the positions checked before are stale
+            guard.cells_moved();
+            (room, lines, shifted.map(Some))
+        };
+        future
+            .into_actor(self)
+            .map(move |(room, lines, shifted), act, ctx| match shifted {
+                Ok(None) => act.send_error(ctx, 400, "A deleted cell is locked by another user."),
+                Ok(Some(changed)) => {
+                    act.broadcast(if inserted {
+                        ActionKind::InsertLines(lines)
+                    } else {
+                        ActionKind::DeleteLines(lines)
+                    });
+                    for grid_value in changed {
+                        act.broadcast(ActionKind::NewGridValue(grid_value));
+                    }
+                }
+                Err(err) => {
+                    error!("Unable to move the cells of {room:?}: {err}");
+                    // The sheet may be partly shifted, every user of the room must reload it
+                    send_error_to_room(
+                        &room,
+                        500,
+                        "Unable to insert or delete rows or columns, the sheet must be reloaded.",
+                    );
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Write the cells of a paste or a fill at once, `targets` being locked by the user.
+    /// The edit is recorded as a whole and the written cells broadcasted in one message.
+    fn write_block<F>(&self, ctx: &mut <Self as Actor>::Context, targets: Vec<Position>, cells: F)
+    where
+        F: Future<Output = Result<Vec<NewGridValue>, mongodb::error::Error>> + 'static,
+    {
+        let Some(sheet_id) = self.sheet.clone() else {
+            self.send_error(
+                ctx,
+                400,
+                "Grid values can only be edited on a whiteboard session.",
+            );
+            return;
+        };
+        let generation = grid::generation(&sheet_id);
+        if !self.holds_locks(&targets) {
+            self.send_error(ctx, 400, "This grid position is not locked by you.");
+            return;
+        }
+        let handle = self.handle.clone();
+        let username = self.username.clone();
+        let future = async move {
+            let cells = cells.await?;
+            let positions: Vec<Position> = cells.iter().map(|cell| cell.position.clone()).collect();
+            let mut before = grid::get_sources(&handle, &sheet_id, &positions).await?;
+            let edited: Vec<_> = cells
+                .iter()
+                .map(|cell| {
+                    let position = cell.position.clone();
+                    (
+                        position.clone(),
+                        before.remove(&position),
+                        cell.value.clone(),
+                    )
+                })
+                .collect();
+            let changed =
+                grid::set_values(&handle, &sheet_id, cells, username, Some(generation)).await?;
+            Ok::<_, mongodb::error::Error>((edited, changed))
+        };
+        future
+            .into_actor(self)
+            .map(|written, act, ctx| match written {
+                Ok((edited, changed)) => {
+                    undo::record(&act.username, &act.room(), Edit::GridValues(edited));
+                    act.broadcast(ActionKind::NewGridValues(changed));
+                }
+                Err(err) => act.send_write_error(ctx, &err, "Unable to save grid values"),
+            })
+            .spawn(ctx);
+    }
+
+    /// Broadcast to the users connected to the same room as this session
+    fn broadcast(&self, action: ActionKind) {
+        broadcast_to_room(&self.room(), &self.username, action);
+    }
+
+    /// Report a failed write of grid values, cells moved since the locks were checked being the
+    /// client's error
+    fn send_write_error(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        err: &mongodb::error::Error,
+        message: &str,
+    ) {
+        if err.get_custom::<grid::CellsMoved>().is_some() {
+            self.send_error(
+                ctx,
+                400,
+                "The cells were moved by an insertion or deletion of rows or columns.",
+            );
+            return;
+        }
+        error!("{message}: {err}");
+        self.send_error(ctx, 500, &format!("{message}."));
+    }
+
+    fn send_error(&self, ctx: &mut <Self as Actor>::Context, error_code: u16, error: &str) {
+        ctx.address().do_send(SendMessage(
+            serde_json::to_string(&ErrorMessages { error_code, error }).unwrap(),
+        ));
+    }
+}
+
+/// Send an error to every user connected to the given room
+fn send_error_to_room(room: &Room, error_code: u16, error: &str) {
+    let message = serde_json::to_string(&ErrorMessages { error_code, error }).unwrap();
+    let rooms = ROOMS.read().expect("unable to get lock on rooms");
+    for member in rooms.get(room).into_iter().flat_map(HashMap::values) {
+        member.addr.do_send(SendMessage(message.clone()));
+    }
+}
+
+/// Send an action to every user connected to the given room, the dropped sessions of the room
+/// keeping it until they are resumed
+pub fn broadcast_to_room(room: &Room, who: &str, action: ActionKind) {
+    let rooms = ROOMS.read().expect("unable to get lock on rooms");
+    let addrs = rooms
+        .get(room)
+        .into_iter()
+        .flat_map(HashMap::values)
+        .map(|member| &member.addr);
+    let mut dropped = DROPPED.write().expect("write in dropped sessions");
+    let dropped = dropped.values_mut().filter(|session| &session.room == room);
+    if let ActionKind::NewGridValues(cells) = action {
+        for session in dropped {
+            let cells: Vec<&NewGridValue> = cells
+                .iter()
+                .filter(|cell| session.viewport.contains(&cell.position))
+                .collect();
+            if !cells.is_empty() {
+                session.miss(cells_message(who, cells));
+            }
+        }
+        // Each session only receives the cells inside its viewport
+        let cells = Arc::new(cells);
+        for addr in addrs {
+            addr.do_send(SendCellsMessage {
+                who: who.to_string(),
+                cells: cells.clone(),
+            });
+        }
+        return;
+    }
+    let payload = SendMessage(
+        serde_json::to_string(&Broadcast {
+            who,
+            kind: action.as_ref(),
+            payload: action.get_action_payload(),
+        })
+        .unwrap(),
+    );
+    let position = match &action {
+        ActionKind::NewGridValue(grid_value) => Some(grid_value.position.clone()),
+        _ => None,
+    };
+    for session in dropped {
+        if position
+            .as_ref()
+            .is_none_or(|position| session.viewport.contains(position))
+        {
+            session.miss(payload.0.clone());
+        }
+    }
+    for addr in addrs {
+        match &position {
+            Some(position) => addr.do_send(SendCellMessage {
+                position: position.clone(),
+                message: payload.clone(),
+            }),
+            None => addr.do_send(payload.clone()),
+        }
+    }
+}
+
+/// Whether any of the positions is locked by another user than `username` in the room
+pub fn locked_by_others(room: &Room, positions: &[Position], username: &str) -> bool {
+    let selections = SELECTIONS.read().expect("read in selections");
+    selections.get(room).is_some_and(|selections| {
+        positions.iter().any(|position| {
+            selections
+                .get(position)
+                .is_some_and(|lock| lock.owner != username)
+        })
+    })
+}
+
+/// Whether a cell of the lines is locked by another user than `username` in the room
+fn locked_in_lines(room: &Room, lines: &Lines, username: &str) -> bool {
+    let selections = SELECTIONS.read().expect("read in selections");
+    selections.get(room).is_some_and(|selections| {
+        selections.iter().any(|(position, lock)| {
+            lock.owner != username && lines.shift_position(position, false).is_none()
+        })
+    })
+}
+
+/// Whether a cell of the rows or columns among the targets is locked by another user than
+/// `username` in the room
+fn locked_in_targets(room: &Room, targets: &[StyleTarget], username: &str) -> bool {
+    let mut rows = HashSet::new();
+    let mut columns = HashSet::new();
+    for target in targets {
+        match target {
+            StyleTarget::Row(row) => rows.insert(*row),
+            StyleTarget::Column(column) => columns.insert(*column),
+            StyleTarget::Cell(_) => continue,
+        };
+    }
+    let selections = SELECTIONS.read().expect("read in selections");
+    selections.get(room).is_some_and(|selections| {
+        selections.iter().any(|(position, lock)| {
+            lock.owner != username
+                && (rows.contains(&position.row) || columns.contains(&position.column))
+        })
+    })
+}
+
+/// Move the selections of a room once rows or columns are inserted (deleted), the selections of
+/// deleted cells being released
+pub fn shift_selections(room: &Room, lines: &Lines, inserted: bool) {
+    let mut selections = SELECTIONS.write().expect("write in selections");
+    if let Some(selections) = selections.get_mut(room) {
+        *selections = selections
+            .drain()
+            .filter_map(|(position, lock)| Some((lines.shift_position(&position, inserted)?, lock)))
+            .collect();
+    }
+}
+
+/// Keep the locks of the user in the room from expiring
+fn refresh_locks(room: &Room, username: &str) {
+    let mut selections = SELECTIONS.write().expect("write in selections");
+    let now = Instant::now();
+    for lock in selections
+        .get_mut(room)
+        .into_iter()
+        .flat_map(HashMap::values_mut)
+    {
+        if lock.owner == username {
+            lock.refreshed = now;
+        }
+    }
+}
+
+/// Release the locks of a room matching `release` and broadcast their deselection.
+/// Returns the released positions of each user.
+pub fn release_locks(
+    room: &Room,
+    release: impl Fn(&Position, &Lock) -> bool,
+) -> HashMap<String, Vec<Position>> {
+    let mut released: HashMap<String, Vec<Position>> = HashMap::new();
+    {
+        let mut selections = SELECTIONS.write().expect("write in selections");
+        let Some(locks) = selections.get_mut(room) else {
+            return released;
+        };
+        for (position, lock) in locks.extract_if(|position, lock| release(position, lock)) {
+            released.entry(lock.owner).or_default().push(position);
+        }
+        if locks.is_empty() {
+            selections.remove(room);
+        }
+    }
+    for (owner, positions) in &released {
+        broadcast_to_room(room, owner, ActionKind::Deselect(positions.clone()));
+    }
+    released
+}
+
+/// Periodically release the locks that weren't refreshed for longer than their TTL
+pub async fn sweep_locks() {
+    let mut interval = actix_web::rt::time::interval((*LOCK_TTL / 4).max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let rooms: Vec<Room> = SELECTIONS
+            .read()
+            .expect("read in selections")
+            .keys()
+            .cloned()
+            .collect();
+        for room in rooms {
+            let released = release_locks(&room, |_, lock| lock.is_expired(now));
+            for (owner, positions) in released {
+                info!(
+                    "Released {} expired locks of {owner} in {room:?}",
+                    positions.len()
+                );
+            }
+        }
+    }
+}
+
+/// Take the dropped session of the token, if it belongs to the same user and room and its grace
+/// period isn't over
+fn resume_session(token: &str, room: &Room, username: &str) -> Option<DroppedSession> {
+    let mut dropped = DROPPED.write().expect("write in dropped sessions");
+    let session = dropped.get(token)?;
+    if &session.room != room
+        || session.username != username
+        || session.dropped.elapsed() > *SESSION_GRACE
+    {
+        return None;
+    }
+    dropped.remove(token)
+}
+
+/// Take over a session that holds the token while its connection isn't known to be dead yet.
+/// The broadcasts it received since the connection dropped are lost.
+fn take_over(
+    sessions: &mut HashMap<Uuid, Member>,
+    token: &str,
+    room: &Room,
+    username: &str,
+) -> Option<DroppedSession> {
+    let (&uuid, _) = sessions
+        .iter()
+        .find(|(_, member)| member.token == token && member.username == username)?;
+    let previous = sessions.remove(&uuid)?;
+    previous.addr.do_send(CloseSession(
+        "Session resumed on another connection.".to_string(),
+    ));
+    Some(DroppedSession {
+        room: room.clone(),
+        username: username.to_string(),
+        viewport: Viewport::default(),
+        dropped: Instant::now(),
+        missed: Vec::new(),
+        overflowed: true,
+    })
+}
+
+/// Whether a session of the user is connected to the room
+fn user_in_room(room: &Room, username: &str) -> bool {
+    let rooms = ROOMS.read().expect("unable to get lock on rooms");
+    rooms
+        .get(room)
+        .into_iter()
+        .flat_map(HashMap::values)
+        .any(|member| member.username == username)
+}
+
+/// Forget the dropped sessions whose grace period is over, releasing their locks unless their
+/// user is still connected to the room. Returns the released positions of each session.
+fn expire_sessions(now: Instant) -> Vec<(DroppedSession, Vec<Position>)> {
+    let expired: Vec<DroppedSession> = DROPPED
+        .write()
+        .expect("write in dropped sessions")
+        .extract_if(|_token, session| now.duration_since(session.dropped) > *SESSION_GRACE)
+        .map(|(_token, session)| session)
+        .collect();
+    expired
+        .into_iter()
+        .map(|session| {
+            if user_in_room(&session.room, &session.username) {
+                return (session, Vec::new());
+            }
+            let released = release_locks(&session.room, |_, lock| lock.owner == session.username)
+                .remove(&session.username)
+                .unwrap_or_default();
+            undo::forget(&session.username, &session.room);
+            (session, released)
+        })
+        .collect()
+}
+
+/// Periodically release the locks of the dropped sessions that weren't resumed in time
+pub async fn sweep_sessions() {
+    let mut interval =
+        actix_web::rt::time::interval((*SESSION_GRACE / 4).max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        for (session, released) in expire_sessions(Instant::now()) {
+            info!(
+                "Session of {} in {:?} expired, released {} locks",
+                session.username,
+                session.room,
+                released.len()
+            );
         }
     }
 }
 
+/// Whether at least one user is connected to the given room
+pub fn room_has_users(room: &Room) -> bool {
+    let rooms = ROOMS.read().expect("unable to get lock on rooms");
+    rooms.contains_key(room)
+}
+
 impl actix::Handler<SendMessage> for MyWs {
     type Result = ();
     fn handle(&mut self, msg: SendMessage, ctx: &mut Self::Context) {
@@ -121,6 +832,89 @@ impl actix::Handler<SendMessage> for MyWs {
     }
 }
 
+/// Message about a grid value, only sent if the position is inside the session's viewport
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct SendCellMessage {
+    pub position: Position,
+    pub message: SendMessage,
+}
+
+impl actix::Handler<SendCellMessage> for MyWs {
+    type Result = ();
+    fn handle(&mut self, msg: SendCellMessage, ctx: &mut Self::Context) {
+        if self.viewport.contains(&msg.position) {
+            ctx.text(msg.message.0)
+        }
+    }
+}
+
+/// Grid values written at once, only the ones inside the session's viewport are sent
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct SendCellsMessage {
+    pub who: String,
+    pub cells: Arc<Vec<NewGridValue>>,
+}
+
+impl actix::Handler<SendCellsMessage> for MyWs {
+    type Result = ();
+    fn handle(&mut self, msg: SendCellsMessage, ctx: &mut Self::Context) {
+        let cells: Vec<&NewGridValue> = msg
+            .cells
+            .iter()
+            .filter(|cell| self.viewport.contains(&cell.position))
+            .collect();
+        if cells.is_empty() {
+            return;
+        }
+        ctx.text(cells_message(&msg.who, cells))
+    }
+}
+
+/// Broadcast of grid values written at once
+fn cells_message(who: &str, cells: Vec<&NewGridValue>) -> String {
+    serde_json::to_string(&Broadcast {
+        who,
+        kind: ActionKind::NewGridValues(Vec::new()).as_ref(),
+        payload: cells,
+    })
+    .unwrap()
+}
+
+/// Close a session with the given reason
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct CloseSession(pub String);
+
+impl actix::Handler<CloseSession> for MyWs {
+    type Result = ();
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        self.closed = true;
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(msg.0),
+        }));
+        ctx.stop();
+    }
+}
+
+/// Close every session of a room that doesn't exist anymore, dropping its selections
+pub fn close_room(room: &Room, reason: &str) {
+    SELECTIONS
+        .write()
+        .expect("unable to get lock on selections")
+        .remove(room);
+    DROPPED
+        .write()
+        .expect("write in dropped sessions")
+        .retain(|_token, session| &session.room != room);
+    let rooms = ROOMS.read().expect("unable to get lock on rooms");
+    for member in rooms.get(room).into_iter().flat_map(HashMap::values) {
+        member.addr.do_send(CloseSession(reason.to_string()));
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct ErrorMessages<'a> {
     error_code: u16,
@@ -129,36 +923,92 @@ struct ErrorMessages<'a> {
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        if let Ok(ws::Message::Text(text)) = msg {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(err) => {
+                info!(
+                    "Protocol error from [{}] -> {}: {err}",
+                    self.ip, self.username
+                );
+                ctx.stop();
+                return;
+            }
+        };
+        // Any frame shows the client is still there
+        self.heartbeat = Instant::now();
+        match msg {
+            ws::Message::Ping(bytes) => {
+                ctx.pong(&bytes);
+                return;
+            }
+            ws::Message::Pong(_) => return,
+            ws::Message::Close(reason) => {
+                self.closed = true;
+                ctx.close(reason);
+                ctx.stop();
+                return;
+            }
+            _ => {}
+        }
+        if let ws::Message::Text(text) = msg {
             if let Ok(action) = serde_json::from_str::<ActionKind>(&text) {
                 let username = self.username.clone();
                 info!("{username} -> {action:#?}");
+                refresh_locks(&self.room(), &username);
                 match action.clone() {
                     ActionKind::NewGridValue(grid_value) => {
-                        {
-                            let selections = SELECTIONS.read().expect("read in selections");
-                            if selections.get(&grid_value.position) != Some(&username) {
-                                ctx.address().do_send(SendMessage(
-                                    serde_json::to_string(&ErrorMessages {
-                                        error_code: 400,
-                                        error: "This grid position is not locked by you.",
-                                    })
-                                    .unwrap(),
-                                ));
-                                return;
-                            }
+                        let Some(sheet_id) = self.sheet.clone() else {
+                            self.send_error(
+                                ctx,
+                                400,
+                                "Grid values can only be edited on a whiteboard session.",
+                            );
+                            return;
+                        };
+                        let generation = grid::generation(&sheet_id);
+                        if !self.holds_lock(&grid_value.position) {
+                            self.send_error(ctx, 400, "This grid position is not locked by you.");
+                            return;
                         }
-                        let grid_value_copy = grid_value.clone();
+                        let handle = self.handle.clone();
+                        let edit_position = grid_value.position.clone();
+                        let after = grid_value.value.clone();
                         let future = async move {
-                            grid::create_value(grid_value_copy, username)
-                                .await
-                                .expect("Unable to create value in database");
+                            let before =
+                                grid::get_source(&handle, &sheet_id, &grid_value.position).await?;
+                            let changed = grid::set_value(
+                                &handle,
+                                &sheet_id,
+                                grid_value,
+                                username,
+                                Some(generation),
+                            )
+                            .await?;
+                            Ok::<_, mongodb::error::Error>((before, changed))
                         };
-                        future.into_actor(self).spawn(ctx);
-                        self.broadcast(action);
+                        future
+                            .into_actor(self)
+                            .map(|written, act, ctx| match written {
+                                Ok((before, changed)) => {
+                                    let edit = Edit::GridValue {
+                                        position: edit_position,
+                                        before,
+                                        after,
+                                    };
+                                    undo::record(&act.username, &act.room(), edit);
+                                    for grid_value in changed {
+                                        act.broadcast(ActionKind::NewGridValue(grid_value));
+                                    }
+                                }
+                                Err(err) => {
+                                    act.send_write_error(ctx, &err, "Unable to save grid value")
+                                }
+                            })
+                            .spawn(ctx);
                     }
                     ActionKind::Select(positions) => {
                         let mut selections = SELECTIONS.write().expect("write in selections");
+                        let selections = selections.entry(self.room()).or_default();
                         if positions
                             .iter()
                             .any(|position| selections.contains_key(position))
@@ -174,15 +1024,201 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
                         }
 
                         let deselection: Vec<_> = selections
-                            .extract_if(|_pos, username| username == &self.username)
-                            .map(|(position, _username)| position)
+                            .extract_if(|_pos, lock| lock.owner == self.username)
+                            .map(|(position, _lock)| position)
                             .collect();
                         positions.into_iter().for_each(|p| {
-                            selections.insert(p, username.clone());
+                            selections.insert(p, Lock::new(username.clone()));
                         });
                         self.broadcast(ActionKind::Deselect(deselection));
                         self.broadcast(action);
                     }
+                    ActionKind::ApplyStyle(change) => {
+                        let Some(sheet_id) = self.sheet.clone() else {
+                            self.send_error(
+                                ctx,
+                                400,
+                                "Styles can only be applied on a whiteboard session.",
+                            );
+                            return;
+                        };
+                        if let Err(err) = style::validate(&change) {
+                            self.send_error(ctx, 400, &format!("Invalid style: {err}"));
+                            return;
+                        }
+                        // Styled cells must be locked by the user, whole rows and columns must not
+                        // cross the cells locked by others
+                        let unlocked = change.targets.iter().any(|target| match target {
+                            StyleTarget::Cell(position) => !self.holds_lock(position),
+                            StyleTarget::Row(_) | StyleTarget::Column(_) => false,
+                        });
+                        if unlocked {
+                            self.send_error(ctx, 400, "This grid position is not locked by you.");
+                            return;
+                        }
+                        if locked_in_targets(&self.room(), &change.targets, &self.username) {
+                            self.send_error(
+                                ctx,
+                                400,
+                                "A cell of the styled lines is locked by another user.",
+                            );
+                            return;
+                        }
+                        let handle = self.handle.clone();
+                        let future =
+                            async move { style::apply_style(&handle, &sheet_id, &change).await };
+                        future
+                            .into_actor(self)
+                            .map(|applied, act, ctx| match applied {
+                                Ok(applied) => act.broadcast(ActionKind::StyleApplied(applied)),
+                                Err(err) => {
+                                    error!("Unable to apply style: {err}");
+                                    act.send_error(ctx, 500, "Unable to apply style.");
+                                }
+                            })
+                            .spawn(ctx);
+                    }
+                    ActionKind::InsertLines(lines) => self.shift_lines(ctx, lines, true),
+                    ActionKind::DeleteLines(lines) => self.shift_lines(ctx, lines, false),
+                    ActionKind::Paste(paste) => match block::paste_cells(&paste) {
+                        Ok(cells) => {
+                            let targets = cells.iter().map(|cell| cell.position.clone()).collect();
+                            self.write_block(ctx, targets, async { Ok(cells) });
+                        }
+                        Err(err) => self.send_error(ctx, 400, &format!("Invalid paste: {err}")),
+                    },
+                    ActionKind::Fill(fill) => match block::fill_targets(&fill) {
+                        Ok(targets) => {
+                            let handle = self.handle.clone();
+                            let sheet_id = self.sheet.clone().unwrap_or_default();
+                            let cells =
+                                async move { block::fill_cells(&handle, &sheet_id, &fill).await };
+                            self.write_block(ctx, targets, cells);
+                        }
+                        Err(err) => self.send_error(ctx, 400, &format!("Invalid fill: {err}")),
+                    },
+                    ActionKind::Viewport(viewport) => self.viewport = viewport,
+                    ActionKind::Undo => self.replay(ctx, Direction::Undo),
+                    ActionKind::Redo => self.replay(ctx, Direction::Redo),
+                    ActionKind::UpdateCell(update) => {
+                        let Some(table_name) = self.table.clone() else {
+                            self.send_error(
+                                ctx,
+                                400,
+                                "Cells can only be edited on a table session.",
+                            );
+                            return;
+                        };
+                        let pool = self.pool.clone();
+                        let future = async move {
+                            let committed = table::update_cell(&pool, &table_name, update).await;
+                            (table_name, committed)
+                        };
+                        future
+                            .into_actor(self)
+                            .map(|(table_name, committed), act, ctx| match committed {
+                                Ok(updated) => {
+                                    let edit = Edit::Cell {
+                                        primary_key: updated.committed.primary_key.clone(),
+                                        column: updated.committed.column.clone(),
+                                        before: updated.before,
+                                        after: updated.after,
+                                    };
+                                    undo::record(&act.username, &act.room(), edit);
+                                    act.broadcast(ActionKind::UpdateCell(updated.committed))
+                                }
+                                Err(err) => {
+                                    debug!("Unable to update cell of {table_name}: {err}");
+                                    act.send_error(
+                                        ctx,
+                                        400,
+                                        &format!("Unable to update cell: {err}"),
+                                    );
+                                }
+                            })
+                            .spawn(ctx);
+                    }
+                    ActionKind::InsertRow(new_row) => {
+                        let Some(table_name) = self.table.clone() else {
+                            self.send_error(
+                                ctx,
+                                400,
+                                "Rows can only be inserted on a table session.",
+                            );
+                            return;
+                        };
+                        let pool = self.pool.clone();
+                        let future = async move {
+                            let inserted = table::insert_row(&pool, &table_name, new_row).await;
+                            (table_name, inserted)
+                        };
+                        future
+                            .into_actor(self)
+                            .map(|(table_name, inserted), act, ctx| match inserted {
+                                Ok(inserted) => {
+                                    let edit = Edit::RowInserted {
+                                        primary_key: inserted.primary_key,
+                                        values: inserted.values,
+                                    };
+                                    undo::record(&act.username, &act.room(), edit);
+                                    act.broadcast(ActionKind::RowInserted(inserted.row))
+                                }
+                                Err(err) => {
+                                    debug!("Unable to insert row in {table_name}: {err}");
+                                    act.send_error(
+                                        ctx,
+                                        400,
+                                        &format!("Unable to insert row: {err}"),
+                                    );
+                                }
+                            })
+                            .spawn(ctx);
+                    }
+                    ActionKind::DeleteRows(primary_keys) => {
+                        let Some(table_name) = self.table.clone() else {
+                            self.send_error(
+                                ctx,
+                                400,
+                                "Rows can only be deleted on a table session.",
+                            );
+                            return;
+                        };
+                        let pool = self.pool.clone();
+                        let future = async move {
+                            let deleted =
+                                table::delete_rows(&pool, &table_name, primary_keys).await;
+                            (table_name, deleted)
+                        };
+                        future
+                            .into_actor(self)
+                            .map(|(table_name, deleted), act, ctx| match deleted {
+                                Ok(deleted) => {
+                                    let primary_keys =
+                                        deleted.iter().map(|row| row.primary_key.clone()).collect();
+                                    if !deleted.is_empty() {
+                                        let rows = deleted
+                                            .into_iter()
+                                            .map(|row| (row.primary_key, row.values))
+                                            .collect();
+                                        undo::record(
+                                            &act.username,
+                                            &act.room(),
+                                            Edit::RowsDeleted(rows),
+                                        );
+                                    }
+                                    act.broadcast(ActionKind::DeleteRows(primary_keys))
+                                }
+                                Err(err) => {
+                                    debug!("Unable to delete rows of {table_name}: {err}");
+                                    act.send_error(
+                                        ctx,
+                                        400,
+                                        &format!("Unable to delete rows: {err}"),
+                                    );
+                                }
+                            })
+                            .spawn(ctx);
+                    }
                     _ => {
                         ctx.address().do_send(SendMessage(
                             serde_json::to_string(&ErrorMessages {
@@ -208,3 +1244,118 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
         }
     }
 }
+
+#[test]
+fn test_lock_expiry() {
+    let room = Room::Sheet("test_lock_expiry".to_string());
+    let now = Instant::now();
+    let stale = Lock {
+        owner: "bob".to_string(),
+        refreshed: now - *LOCK_TTL - Duration::from_secs(1),
+    };
+    let (a1, b1) = (
+        Position { column: 0, row: 0 },
+        Position { column: 1, row: 0 },
+    );
+    SELECTIONS.write().unwrap().insert(
+        room.clone(),
+        HashMap::from([
+            (a1.clone(), Lock::new("alice".to_string())),
+            (b1.clone(), stale),
+        ]),
+    );
+
+    refresh_locks(&room, "bob");
+    assert!(release_locks(&room, |_, lock| lock.is_expired(now)).is_empty());
+    let later = now + *LOCK_TTL + Duration::from_secs(1);
+    let released = release_locks(&room, |_, lock| {
+        lock.owner == "alice" && lock.is_expired(later)
+    });
+    assert_eq!(released, HashMap::from([("alice".to_string(), vec![a1])]));
+    assert!(!locked_by_others(&room, std::slice::from_ref(&b1), "bob"));
+    assert!(locked_by_others(&room, &[b1], "alice"));
+    assert!(locked_in_targets(&room, &[StyleTarget::Column(1)], "alice"));
+    assert!(!locked_in_targets(&room, &[StyleTarget::Column(1)], "bob"));
+    assert!(!locked_in_targets(&room, &[StyleTarget::Row(1)], "alice"));
+}
+
+#[test]
+fn test_session_resume() {
+    let room = Room::Sheet("test_session_resume".to_string());
+    let viewport = Viewport {
+        first_row: 0,
+        last_row: 9,
+        first_column: 0,
+        last_column: 9,
+    };
+    DROPPED.write().unwrap().insert(
+        "token".to_string(),
+        DroppedSession {
+            room: room.clone(),
+            username: "alice".to_string(),
+            viewport,
+            dropped: Instant::now(),
+            missed: Vec::new(),
+            overflowed: false,
+        },
+    );
+    let cell = |row| NewGridValue {
+        position: Position { column: 0, row },
+        value: None,
+        formula: None,
+    };
+    broadcast_to_room(&room, "bob", ActionKind::NewGridValue(cell(1)));
+    broadcast_to_room(&room, "bob", ActionKind::NewGridValue(cell(20)));
+    broadcast_to_room(
+        &room,
+        "bob",
+        ActionKind::NewGridValues(vec![cell(2), cell(30)]),
+    );
+    broadcast_to_room(&room, "bob", ActionKind::Deselect(Vec::new()));
+    let other = Room::Sheet("test_session_resume_other".to_string());
+    broadcast_to_room(&other, "bob", ActionKind::Deselect(Vec::new()));
+
+    assert!(resume_session("token", &room, "bob").is_none());
+    assert!(resume_session("token", &other, "alice").is_none());
+    let resumed = resume_session("token", &room, "alice").unwrap();
+    assert_eq!(resumed.missed.len(), 3);
+    assert!(resumed.missed[1].contains("NewGridValues") && !resumed.missed[1].contains("30"));
+    assert!(!resumed.overflowed);
+    assert!(resume_session("token", &room, "alice").is_none());
+}
+
+#[test]
+fn test_session_expiry() {
+    let room = Room::Sheet("test_session_expiry".to_string());
+    let now = Instant::now();
+    let dropped = |username: &str| DroppedSession {
+        room: room.clone(),
+        username: username.to_string(),
+        viewport: Viewport::default(),
+        dropped: now,
+        missed: Vec::new(),
+        overflowed: false,
+    };
+    let a1 = Position { column: 0, row: 0 };
+    SELECTIONS.write().unwrap().insert(
+        room.clone(),
+        HashMap::from([(a1.clone(), Lock::new("carol".to_string()))]),
+    );
+    DROPPED
+        .write()
+        .unwrap()
+        .insert("test_session_expiry".to_string(), dropped("carol"));
+
+    assert!(expire_sessions(now).is_empty());
+    assert!(locked_by_others(&room, std::slice::from_ref(&a1), "dave"));
+    DROPPED
+        .write()
+        .unwrap()
+        .get_mut("test_session_expiry")
+        .unwrap()
+        .dropped = now - *SESSION_GRACE - Duration::from_secs(1);
+    let expired = expire_sessions(now);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].1, vec![a1.clone()]);
+    assert!(!locked_by_others(&room, &[a1], "dave"));
+}
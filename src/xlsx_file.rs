@@ -0,0 +1,294 @@
+use std::io::Cursor;
+
+use calamine::{open_workbook_from_rs, Data, Reader, Xlsx};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use serde::Deserialize;
+
+use crate::block;
+use crate::formula;
+use crate::introspection::ColumnInfo;
+use crate::models::{CellValue, GridValue, NewGridValue, Position};
+
+/// Excel limits worksheets to 1 048 576 rows and 16 384 columns
+const MAX_ROWS: u64 = 1_048_576;
+const MAX_COLUMNS: u64 = 16_384;
+/// Rows of a table that fit in a worksheet below the column names
+pub const MAX_TABLE_ROWS: usize = MAX_ROWS as usize - 1;
+
+const DATE_FORMAT: &str = "yyyy-mm-dd";
+const DATETIME_FORMAT: &str = "yyyy-mm-dd hh:mm:ss";
+
+/// Query parameters of the XLSX import
+#[derive(Debug, Deserialize)]
+pub struct ImportOptions {
+    /// User recorded as the author of the imported cells
+    pub user: String,
+    /// Cell receiving the first cell of the worksheet, `A1` by default
+    pub at: Option<String>,
+}
+
+/// Value of a cell with the type it is written with
+#[derive(Debug, PartialEq)]
+enum Typed<'a> {
+    Number(f64),
+    Bool(bool),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    Text(&'a str),
+}
+
+fn parse_datetime(text: &str) -> Option<NaiveDateTime> {
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+}
+
+/// Type a whiteboard cell is written with, `None` for formulas which are stored as their result
+fn typed_cell(value: &CellValue) -> Option<Typed<'_>> {
+    Some(match value {
+        CellValue::String(text) | CellValue::Error(text) => Typed::Text(text),
+        CellValue::Number(number) => Typed::Number(*number),
+        CellValue::Boolean(bool) => Typed::Bool(*bool),
+        CellValue::Date(date) => Typed::Date(*date),
+        CellValue::Datetime(datetime) => Typed::DateTime(*datetime),
+        CellValue::Formula(_) => return None,
+    })
+}
+
+/// Type of a Postgres value, as decoded by `decode::decode_value`
+fn typed_json<'a>(column: &ColumnInfo, value: &'a serde_json::Value) -> Option<Typed<'a>> {
+    Some(match value {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Bool(bool) => Typed::Bool(*bool),
+        serde_json::Value::Number(number) => Typed::Number(number.as_f64()?),
+        serde_json::Value::String(text) => match column.udt_name.as_str() {
+            "numeric" => text
+                .parse()
+                .ok()
+                .filter(|number: &f64| number.is_finite())
+                .map_or(Typed::Text(text), Typed::Number),
+            "date" => {
+                NaiveDate::parse_from_str(text, "%Y-%m-%d").map_or(Typed::Text(text), Typed::Date)
+            }
+            "timestamp" => parse_datetime(text).map_or(Typed::Text(text), Typed::DateTime),
+            "timestamptz" => DateTime::parse_from_rfc3339(text)
+                .map_or(Typed::Text(text), |datetime| {
+                    Typed::DateTime(datetime.naive_utc())
+                }),
+            _ => Typed::Text(text),
+        },
+        // Arrays, json and undecodable values are written as JSON
+        _ => return None,
+    })
+}
+
+struct Formats {
+    date: Format,
+    datetime: Format,
+}
+
+impl Formats {
+    fn new() -> Self {
+        Formats {
+            date: Format::new().set_num_format(DATE_FORMAT),
+            datetime: Format::new().set_num_format(DATETIME_FORMAT),
+        }
+    }
+}
+
+fn write_typed(
+    worksheet: &mut Worksheet,
+    formats: &Formats,
+    row: u32,
+    column: u16,
+    value: Typed,
+) -> Result<(), XlsxError> {
+    match value {
+        Typed::Number(number) => worksheet.write_number(row, column, number)?,
+        Typed::Bool(bool) => worksheet.write_boolean(row, column, bool)?,
+        Typed::Date(date) => {
+            worksheet.write_datetime_with_format(row, column, date, &formats.date)?
+        }
+        Typed::DateTime(datetime) => {
+            worksheet.write_datetime_with_format(row, column, datetime, &formats.datetime)?
+        }
+        Typed::Text(text) => worksheet.write_string(row, column, text)?,
+    };
+    Ok(())
+}
+
+/// Write the cells of a whiteboard sheet as a workbook with a single worksheet.
+pub fn write_sheet(name: &str, cells: &[GridValue]) -> Result<Vec<u8>, String> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    // Excel restricts the worksheet names, the default name is kept for the others
+    let _ = worksheet.set_name(name);
+    let formats = Formats::new();
+    for cell in cells {
+        let Some(value) = cell.value.as_ref().and_then(typed_cell) else {
+            continue;
+        };
+        let Position { column, row } = cell.position;
+        if row >= MAX_ROWS || column >= MAX_COLUMNS {
+            return Err(format!(
+                "the cell at row {row} and column {column} is out of the bounds of a worksheet"
+            ));
+        }
+        write_typed(worksheet, &formats, row as u32, column as u16, value)
+            .map_err(|err| err.to_string())?;
+    }
+    workbook.save_to_buffer().map_err(|err| err.to_string())
+}
+
+/// Write rows of a Postgres table as a workbook, the first row holding the column names.
+pub fn write_table(
+    name: &str,
+    columns: &[ColumnInfo],
+    rows: &[Vec<serde_json::Value>],
+) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    let _ = worksheet.set_name(name);
+    let formats = Formats::new();
+    let header = Format::new().set_bold();
+    for (index, column) in columns.iter().enumerate() {
+        worksheet.write_string_with_format(0, index as u16, &column.column_name, &header)?;
+    }
+    for (row_index, row) in rows.iter().enumerate() {
+        let row_index = row_index as u32 + 1;
+        for (index, (column, value)) in columns.iter().zip(row).enumerate() {
+            match typed_json(column, value) {
+                Some(typed) => write_typed(worksheet, &formats, row_index, index as u16, typed)?,
+                None if value.is_null() => continue,
+                None => {
+                    worksheet.write_string(row_index, index as u16, value.to_string())?;
+                }
+            }
+        }
+    }
+    workbook.save_to_buffer()
+}
+
+/// Value stored in a whiteboard cell for a worksheet value, `None` for empty cells
+fn cell_value(data: &Data) -> Option<CellValue> {
+    match data {
+        Data::Empty => None,
+        Data::Int(int) => Some(CellValue::Number(*int as f64)),
+        Data::Float(float) => Some(CellValue::Number(*float)),
+        Data::Bool(bool) => Some(CellValue::Boolean(*bool)),
+        Data::DateTime(excel_datetime) => Some(match excel_datetime.as_datetime() {
+            Some(datetime) if datetime.time() == NaiveTime::MIN => CellValue::Date(datetime.date()),
+            Some(datetime) => CellValue::Datetime(datetime),
+            None => CellValue::Number(excel_datetime.as_f64()),
+        }),
+        Data::String(text) => (!text.is_empty()).then(|| CellValue::String(text.clone())),
+        Data::DateTimeIso(text) | Data::DurationIso(text) => CellValue::parse_text(text),
+        Data::Error(error) => Some(CellValue::Error(error.to_string())),
+    }
+}
+
+/// Read the first worksheet of a workbook into the cells to write, its first cell going to `at`.
+pub fn read_sheet(data: &[u8], options: &ImportOptions) -> Result<Vec<NewGridValue>, String> {
+    let origin = match &options.at {
+        Some(at) => {
+            formula::parse_reference(at).ok_or_else(|| format!("invalid target cell {at}"))?
+        }
+        None => Position::default(),
+    };
+    let mut workbook: Xlsx<_> = open_workbook_from_rs(Cursor::new(data))
+        .map_err(|err: calamine::XlsxError| err.to_string())?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or("the workbook has no worksheet")?
+        .map_err(|err| err.to_string())?;
+    let (first_row, first_column) = range.start().unwrap_or_default();
+
+    let mut cells = Vec::new();
+    for (row, column, data) in range.used_cells() {
+        let Some(value) = cell_value(data) else {
+            continue;
+        };
+        let position = Position {
+            column: origin
+                .column
+                .saturating_add(u64::from(first_column) + column as u64),
+            row: origin.row.saturating_add(u64::from(first_row) + row as u64),
+        };
+        if !block::in_sheet(&position) {
+            return Err("the worksheet doesn't fit in the sheet".to_string());
+        }
+        cells.push(NewGridValue {
+            position,
+            value: Some(value),
+            formula: None,
+        });
+    }
+    Ok(cells)
+}
+
+#[test]
+fn test_xlsx_round_trip() {
+    let options = ImportOptions {
+        user: "alice".to_string(),
+        at: None,
+    };
+    let fixture = read_sheet(include_bytes!("../fixtures/board.xlsx"), &options).unwrap();
+    let value = |cells: &[NewGridValue], reference: &str| {
+        let position = formula::parse_reference(reference).unwrap();
+        cells
+            .iter()
+            .find(|cell| cell.position == position)
+            .and_then(|cell| cell.value.clone())
+    };
+    let date = NaiveDate::from_ymd_opt(2023, 8, 1).unwrap();
+    assert_eq!(
+        value(&fixture, "A1"),
+        Some(CellValue::String("name".to_string()))
+    );
+    assert_eq!(value(&fixture, "B2"), Some(CellValue::Number(42.)));
+    assert_eq!(value(&fixture, "B3"), Some(CellValue::Number(2.5)));
+    assert_eq!(value(&fixture, "C2"), Some(CellValue::Boolean(true)));
+    assert_eq!(value(&fixture, "D2"), Some(CellValue::Date(date)));
+    assert_eq!(
+        value(&fixture, "D3"),
+        Some(CellValue::Datetime(date.and_hms_opt(12, 30, 0).unwrap()))
+    );
+    assert_eq!(value(&fixture, "C3"), Some(CellValue::Boolean(false)));
+    assert_eq!(value(&fixture, "A4"), None);
+
+    // Writing the cells back keeps their types
+    let cells: Vec<GridValue> = fixture
+        .iter()
+        .map(|cell| GridValue {
+            position: cell.position.clone(),
+            value: cell.value.clone(),
+            ..Default::default()
+        })
+        .collect();
+    let written = write_sheet("board", &cells).unwrap();
+    let options = ImportOptions {
+        user: "alice".to_string(),
+        at: Some("C3".to_string()),
+    };
+    let round_trip = read_sheet(&written, &options).unwrap();
+    assert_eq!(round_trip.len(), fixture.len());
+    for cell in &fixture {
+        let Position { column, row } = cell.position;
+        let moved = Position {
+            column: column + 2,
+            row: row + 2,
+        };
+        let moved = round_trip
+            .iter()
+            .find(|cell| cell.position == moved)
+            .unwrap();
+        assert_eq!(moved.value, cell.value);
+    }
+
+    let options = ImportOptions {
+        user: "alice".to_string(),
+        at: Some("A9223372036854775807".to_string()),
+    };
+    assert!(read_sheet(&written, &options).is_err());
+}